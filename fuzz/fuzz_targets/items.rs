@@ -0,0 +1,18 @@
+#![no_main]
+
+use compsize_rs_fuzz::btrfs::RawBatch;
+use libfuzzer_sys::fuzz_target;
+
+// a search buffer as SEARCH_V2 or a recording hands it over, item count first
+fuzz_target!(|data: &[u8]| {
+    let Some((nr_items, buf)) = data.split_first_chunk::<4>() else {
+        return;
+    };
+    let batch = RawBatch {
+        nr_items: u32::from_le_bytes(*nr_items),
+        buf: buf.to_vec(),
+    };
+    for item in batch.items() {
+        let _ = item.parse();
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use compsize_rs_fuzz::btrfs::{IoctlSearchItem, SEARCH_ITEM_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+// a single header and extent item, short input is zero padded
+fuzz_target!(|data: &[u8]| {
+    let mut buf = [0; SEARCH_ITEM_SIZE];
+    let len = data.len().min(SEARCH_ITEM_SIZE);
+    buf[..len].copy_from_slice(&data[..len]);
+    let _ = IoctlSearchItem::from_le_bytes(&buf).parse();
+});
@@ -0,0 +1,12 @@
+// the main crate is a binary, so the parser is built here on its own
+#[allow(dead_code, clippy::new_without_default)]
+#[path = "../../src/btrfs.rs"]
+pub mod btrfs;
+
+// what btrfs.rs needs from main.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ExtentStat {
+    pub disk: u64,
+    pub uncomp: u64,
+    pub refd: u64,
+}
@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::{
+    btrfs::{Compression, Fsid},
+    scale::Scale,
+    ExtentMap, ExtentStat,
+};
+
+// upper bounds (exclusive) of the age buckets in transactions, the last one is open
+const AGE_BUCKETS: [u64; 6] = [10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct AgeBucket {
+    nextent: u64,
+    stat: ExtentStat,
+    // the part of the above written by a compression algorithm
+    compressed: ExtentStat,
+    min_gen: u64,
+    max_gen: u64,
+}
+
+// unique extents bucketed by how many transactions ago they were written
+#[derive(Debug, Default)]
+pub struct AgeStat {
+    buckets: [AgeBucket; AGE_BUCKETS.len() + 1],
+}
+
+impl AgeStat {
+    // `fsids` maps the extents to their filesystem, `generations` is the current
+    // generation of every filesystem, 0 if unknown
+    pub fn collect(
+        extent_map: &ExtentMap,
+        fsids: &[Fsid],
+        generations: &BTreeMap<Fsid, u64>,
+    ) -> Self {
+        // older kernels don't report the generation, the newest extent is the best guess
+        let mut current: Vec<u64> = fsids
+            .iter()
+            .map(|fsid| generations.get(fsid).copied().unwrap_or(0))
+            .collect();
+        for entry in extent_map.iter() {
+            if let Some(refs) = entry.value() {
+                let gen = &mut current[entry.key().fs as usize];
+                *gen = (*gen).max(refs.generation);
+            }
+        }
+        let mut ret = Self::default();
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            let age = current[entry.key().fs as usize].saturating_sub(refs.generation);
+            let i = AGE_BUCKETS
+                .iter()
+                .position(|&max| age < max)
+                .unwrap_or(AGE_BUCKETS.len());
+            let bucket = &mut ret.buckets[i];
+            let stat = ExtentStat {
+                disk: refs.disk,
+                uncomp: refs.uncomp,
+                refd: refs.refd,
+            };
+            if bucket.nextent == 0 {
+                bucket.min_gen = refs.generation;
+            }
+            bucket.nextent += 1;
+            bucket.stat.merge(stat);
+            if refs.comp != Compression::None {
+                bucket.compressed.merge(stat);
+            }
+            bucket.min_gen = bucket.min_gen.min(refs.generation);
+            bucket.max_gen = bucket.max_gen.max(refs.generation);
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> AgeStatDisplay<'_> {
+        AgeStatDisplay { stat: self, scale }
+    }
+}
+
+pub struct AgeStatDisplay<'a> {
+    stat: &'a AgeStat,
+    scale: Scale,
+}
+impl Display for AgeStatDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { stat, scale } = self;
+        writeln!(
+            f,
+            "{:12} {:23} {:10} {:12} {:12} {:6} {:10}",
+            "Age", "Generations", "Extents", "Disk Usage", "Uncompressed", "Perc", "Compressed"
+        )?;
+        let mut min = 0;
+        for (i, bucket) in stat.buckets.iter().enumerate() {
+            let range = match AGE_BUCKETS.get(i) {
+                Some(&max) => format!("{}-{}", min, max - 1),
+                None => format!("{}+", min),
+            };
+            if let Some(&max) = AGE_BUCKETS.get(i) {
+                min = max;
+            }
+            if bucket.nextent == 0 {
+                continue;
+            }
+            let perc = bucket.stat.disk as f64 * 100.0 / bucket.stat.uncomp as f64;
+            // share of the uncompressed bytes that went through a compression algorithm
+            let compressed = bucket.compressed.uncomp as f64 * 100.0 / bucket.stat.uncomp as f64;
+            writeln!(
+                f,
+                "{:12} {:23} {:10} {:12} {:12} {:5.0}% {:9.1}%",
+                range,
+                format!("{}-{}", bucket.min_gen, bucket.max_gen),
+                bucket.nextent,
+                scale.scale(bucket.stat.disk),
+                scale.scale(bucket.stat.uncomp),
+                perc,
+                compressed
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::{cmp::Reverse, fmt::Display, path::PathBuf};
+
+use crate::{
+    btrfs::Compression, kind_name, scale::Scale, ExtentMap, ExtentRefs, FileExtents, NKIND,
+};
+
+// insert [start, end) into a sorted list of disjoint ranges
+pub fn add_range(ranges: &mut Vec<(u64, u64)>, (start, end): (u64, u64)) {
+    let first = ranges.partition_point(|&(_, e)| e < start);
+    let last = ranges.partition_point(|&(s, _)| s <= end);
+    if first == last {
+        ranges.insert(first, (start, end));
+        return;
+    }
+    let merged = (start.min(ranges[first].0), end.max(ranges[last - 1].1));
+    ranges.splice(first..last, [merged]);
+}
+
+// disk bytes held by the parts of the extent no file references anymore
+fn wasted(refs: &ExtentRefs) -> u64 {
+    let covered: u64 = refs
+        .ranges
+        .iter()
+        .map(|&(s, e)| e.min(refs.uncomp) - s.min(refs.uncomp))
+        .sum();
+    let unreferenced = refs.uncomp.saturating_sub(covered);
+    if refs.comp == Compression::None || refs.uncomp == 0 {
+        unreferenced.min(refs.disk)
+    } else {
+        // compressed data can't be mapped back to disk bytes, assume it compresses evenly
+        (refs.disk as u128 * unreferenced as u128 / refs.uncomp as u128) as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct WasteRow {
+    // extents with unreferenced ranges
+    nextent: u64,
+    disk: u64,
+    wasted: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct WasteStat {
+    rows: [WasteRow; NKIND],
+}
+
+impl WasteStat {
+    pub fn collect(extent_map: &ExtentMap) -> Self {
+        let mut ret = Self::default();
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            let wasted = wasted(refs);
+            if wasted == 0 {
+                continue;
+            }
+            let row = &mut ret.rows[refs.kind()];
+            row.nextent += 1;
+            row.disk += refs.disk;
+            row.wasted += wasted;
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> WasteStatDisplay<'_> {
+        WasteStatDisplay { stat: self, scale }
+    }
+}
+
+pub struct WasteStatDisplay<'a> {
+    stat: &'a WasteStat,
+    scale: Scale,
+}
+impl Display for WasteStatDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { stat, scale } = self;
+        writeln!(f, "Partially referenced extents:")?;
+        writeln!(
+            f,
+            "{:10} {:12} {:12} {:12}",
+            "Type", "Extents", "Disk Usage", "Wasted"
+        )?;
+        let total = stat.rows.iter().fold(WasteRow::default(), |a, r| WasteRow {
+            nextent: a.nextent + r.nextent,
+            disk: a.disk + r.disk,
+            wasted: a.wasted + r.wasted,
+        });
+        let rows = stat.rows.iter().enumerate().map(|(i, r)| (kind_name(i), r));
+        for (name, row) in [("TOTAL", &total)].into_iter().chain(rows) {
+            if row.nextent == 0 && name != "TOTAL" {
+                continue;
+            }
+            writeln!(
+                f,
+                "{:10} {:12} {:12} {:12}",
+                name,
+                row.nextent,
+                scale.scale(row.disk),
+                scale.scale(row.wasted)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// files referencing the most wasteful extents, shared extents count for every file
+pub fn top_files(extent_map: &ExtentMap, files: Vec<FileExtents>, n: usize) -> Vec<(PathBuf, u64)> {
+    let mut ret = Vec::new();
+    for mut file in files {
+        file.extents.sort_unstable_by_key(|id| id.bytenr);
+        file.extents.dedup();
+        let wasted = file
+            .extents
+            .iter()
+            .filter_map(|id| extent_map.get(id)?.as_deref().map(wasted))
+            .sum::<u64>();
+        if wasted != 0 {
+            ret.push((file.path, wasted));
+        }
+    }
+    ret.sort_unstable_by_key(|(_, wasted)| Reverse(*wasted));
+    ret.truncate(n);
+    ret
+}
+
+pub struct TopFilesDisplay<'a> {
+    pub top: &'a [(PathBuf, u64)],
+    pub scale: Scale,
+}
+impl Display for TopFilesDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Files pinning unreferenced extent ranges:")?;
+        for (path, wasted) in self.top {
+            writeln!(f, "{:>12}  {}", self.scale.scale(*wasted), path.display())?;
+        }
+        Ok(())
+    }
+}
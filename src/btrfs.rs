@@ -1,20 +1,163 @@
-use std::{fmt::Debug, iter::FusedIterator};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt::{Debug, Display},
+    iter::FusedIterator,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 type File = std::fs::File;
 
 use rustix::{
     io::Errno,
-    ioctl::{ioctl, ReadWriteOpcode, Updater},
+    ioctl::{ioctl, ReadOpcode, ReadWriteOpcode, Updater},
 };
 
 use crate::ExtentStat;
 
 pub const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+pub const BTRFS_INODE_ITEM_KEY: u32 = 1;
+pub const BTRFS_INODE_REF_KEY: u32 = 12;
 pub const BTRFS_EXTENT_DATA_KEY: u32 = 108;
+pub const BTRFS_ROOT_ITEM_KEY: u32 = 132;
+pub const BTRFS_ROOT_REF_KEY: u32 = 156;
+pub const BTRFS_CHUNK_ITEM_KEY: u32 = 228;
+pub const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+pub const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+pub const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+pub const BTRFS_FS_INFO_FLAG_GENERATION: u64 = 1 << 1;
 pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
 pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
 pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Fsid(pub [u8; 16]);
+impl Display for Fsid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for Fsid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: Vec<_> = s.bytes().filter(|&b| b != b'-').collect();
+        if hex.len() != 32 {
+            return Err(format!("Invalid fsid: {}", s));
+        }
+        let mut ret = [0; 16];
+        for (b, pair) in ret.iter_mut().zip(hex.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| format!("Invalid fsid: {}", s))?;
+            *b = u8::from_str_radix(pair, 16).map_err(|_| format!("Invalid fsid: {}", s))?;
+        }
+        Ok(Self(ret))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FsInfo {
+    pub fsid: Fsid,
+    // 0 on kernels older than 5.10
+    pub generation: u64,
+}
+
+pub fn fs_info(fd: &File) -> rustix::io::Result<FsInfo> {
+    let mut args = FsInfoArgs {
+        max_id: 0,
+        num_devices: 0,
+        fsid: [0; 16],
+        nodesize: 0,
+        sectorsize: 0,
+        clone_alignment: 0,
+        csum_type: 0,
+        csum_size: 0,
+        flags: BTRFS_FS_INFO_FLAG_GENERATION,
+        generation: 0,
+        metadata_uuid: [0; 16],
+        reserved: [0; 944],
+    };
+    unsafe {
+        let ctl = Updater::<'_, ReadOpcode<BTRFS_IOCTL_MAGIC, 31, FsInfoArgs>, _>::new(&mut args);
+        ioctl(fd, ctl)?;
+    }
+    let generation = if args.flags & BTRFS_FS_INFO_FLAG_GENERATION != 0 {
+        args.generation
+    } else {
+        0
+    };
+    Ok(FsInfo {
+        fsid: Fsid(args.fsid),
+        generation,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; 4080],
+}
+
+// tree 0 is the subvolume of `fd`
+fn ino_lookup(fd: &File, treeid: u64, objectid: u64) -> rustix::io::Result<InoLookupArgs> {
+    let mut args = InoLookupArgs {
+        treeid,
+        objectid,
+        name: [0; 4080],
+    };
+    unsafe {
+        let ctl =
+            Updater::<'_, ReadWriteOpcode<BTRFS_IOCTL_MAGIC, 18, InoLookupArgs>, _>::new(&mut args);
+        ioctl(fd, ctl)?;
+    }
+    Ok(args)
+}
+
+// id of the subvolume `fd` lives in
+pub fn subvol_id(fd: &File) -> rustix::io::Result<u64> {
+    Ok(ino_lookup(fd, 0, BTRFS_FIRST_FREE_OBJECTID)?.treeid)
+}
+
+// path of directory `dir` relative to the root of the subvolume `fd` lives in
+// other trees than the one of `fd` need CAP_SYS_ADMIN
+pub fn dir_path(fd: &File, tree_id: u64, dir: u64) -> rustix::io::Result<PathBuf> {
+    let args = ino_lookup(fd, tree_id, dir)?;
+    let len = args
+        .name
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(args.name.len());
+    Ok(PathBuf::from(OsStr::from_bytes(&args.name[..len])))
+}
+
 // le on disk
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
@@ -25,22 +168,29 @@ pub struct IoctlSearchHeader {
     r#type: u32,
     len: u32,
 }
+// little endian integer of `n` bytes at `at`, None if `buf` is too short
+// search results pack items back to back, so nothing in them is aligned
+pub fn le_at(buf: &[u8], at: usize, n: usize) -> Option<u64> {
+    let mut ret = [0; 8];
+    ret[..n].copy_from_slice(buf.get(at..at.checked_add(n)?)?);
+    Some(u64::from_le_bytes(ret))
+}
+
 impl IoctlSearchHeader {
-    unsafe fn from_le_raw(buf: &[u8]) -> Self {
-        let raw = &*(buf.as_ptr() as *const IoctlSearchHeader);
-        Self {
-            transid: u64::from_le(raw.transid),
-            objectid: u64::from_le(raw.objectid),
-            offset: u64::from_le(raw.offset),
-            r#type: u32::from_le(raw.r#type),
-            len: u32::from_le(raw.len),
-        }
+    fn from_le_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            transid: le_at(buf, 0, 8)?,
+            objectid: le_at(buf, 8, 8)?,
+            offset: le_at(buf, 16, 8)?,
+            r#type: le_at(buf, 24, 4)? as u32,
+            len: le_at(buf, 28, 4)? as u32,
+        })
     }
 }
 
 // le on disk
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(C, packed)]
 pub struct FileExtentItem {
     pub generation: u64,
     pub ram_bytes: u64,
@@ -54,30 +204,33 @@ pub struct FileExtentItem {
     pub offset: u64,
     pub num_bytes: u64,
 }
-const EXTENT_INLINE_HEADER_SIZE: usize = 21;
+
 impl FileExtentItem {
-    unsafe fn from_le_raw(buf: &[u8]) -> Self {
-        let raw = &*(buf.as_ptr() as *const FileExtentItem);
+    // fields past the end of a short item, like an inline one, are 0
+    fn from_le_bytes(data: &[u8]) -> Self {
+        let field = |at: usize, n: usize| le_at(data, at, n).unwrap_or(0);
         Self {
-            generation: u64::from_le(raw.generation),
-            ram_bytes: u64::from_le(raw.ram_bytes),
-            compression: u8::from_le(raw.compression),
-            encryption: u8::from_le(raw.encryption),
-            other_encoding: u16::from_le(raw.other_encoding),
-            r#type: u8::from_le(raw.r#type),
-            disk_bytenr: u64::from_le(raw.disk_bytenr),
-            disk_num_bytes: u64::from_le(raw.disk_num_bytes),
-            offset: u64::from_le(raw.offset),
-            num_bytes: u64::from_le(raw.num_bytes),
+            generation: field(0, 8),
+            ram_bytes: field(8, 8),
+            compression: field(16, 1) as u8,
+            encryption: field(17, 1) as u8,
+            other_encoding: field(18, 2) as u16,
+            r#type: field(20, 1) as u8,
+            disk_bytenr: field(21, 8),
+            disk_num_bytes: field(29, 8),
+            offset: field(37, 8),
+            num_bytes: field(45, 8),
         }
     }
 }
 
-#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
 pub struct IoctlSearchItem {
     pub(self) header: IoctlSearchHeader,
     pub(self) item: FileExtentItem,
 }
+pub const SEARCH_ITEM_SIZE: usize = size_of::<IoctlSearchItem>();
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -86,7 +239,10 @@ pub enum Compression {
     Zlib,
     Lzo,
     Zstd,
+    // compressed with an unknown algorithm, only seen through FIEMAP
+    Encoded,
 }
+pub const NCOMP: usize = 5;
 impl Compression {
     pub fn as_usize(self) -> usize {
         self as usize
@@ -97,15 +253,24 @@ impl Compression {
             1 => Self::Zlib,
             2 => Self::Lzo,
             3 => Self::Zstd,
+            4 => Self::Encoded,
             _ => panic!("Invalid compression type: {}", n),
         }
     }
+    // as stored in a file extent item
+    pub fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0..=3 => Some(Self::from_usize(n as usize)),
+            _ => None,
+        }
+    }
     pub fn name(&self) -> &'static str {
         match self {
             Compression::None => "none",
             Compression::Zlib => "zlib",
             Compression::Lzo => "lzo",
             Compression::Zstd => "zstd",
+            Compression::Encoded => "encoded",
         }
     }
 }
@@ -115,17 +280,23 @@ pub enum ExtentType {
     Inline,
     Regular,
     Prealloc,
+    // a regular extent without disk_bytenr, never stored on disk as a type of its own
+    Hole,
 }
 
 impl ExtentType {
-    pub fn from_u8(n: u8) -> Self {
+    pub fn from_u8(n: u8) -> Option<Self> {
         match n {
-            BTRFS_FILE_EXTENT_INLINE => Self::Inline,
-            BTRFS_FILE_EXTENT_REG => Self::Regular,
-            BTRFS_FILE_EXTENT_PREALLOC => Self::Prealloc,
-            _ => panic!("Invalid extent type: {}", n),
+            BTRFS_FILE_EXTENT_INLINE => Some(Self::Inline),
+            BTRFS_FILE_EXTENT_REG => Some(Self::Regular),
+            BTRFS_FILE_EXTENT_PREALLOC => Some(Self::Prealloc),
+            _ => None,
         }
     }
+    // whether the item points to an extent on disk
+    pub fn has_extent(self) -> bool {
+        matches!(self, Self::Regular | Self::Prealloc)
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -147,39 +318,129 @@ impl ExtentKey {
     }
 }
 
+// one parsed file extent item
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub key: ExtentKey,
+    pub comp: Compression,
+    pub stat: ExtentStat,
+    // start of the referenced range inside the uncompressed extent, 0 for inline
+    pub offset: u64,
+    // position in the file
+    pub pos: u64,
+    // transaction the extent was written in
+    pub generation: u64,
+}
+
 impl IoctlSearchItem {
-    unsafe fn from_le_raw(buf: &[u8]) -> Self {
-        let header = IoctlSearchHeader::from_le_raw(&buf[..size_of::<IoctlSearchHeader>()]);
-        let item = FileExtentItem::from_le_raw(&buf[size_of::<IoctlSearchHeader>()..]);
+    // an EXTENT_DATA item the way the kernel returns it, `len` is the size of the item data
+    pub fn extent_data(ino: u64, pos: u64, len: u32, item: FileExtentItem) -> Self {
+        let header = IoctlSearchHeader {
+            transid: item.generation,
+            objectid: ino,
+            offset: pos,
+            r#type: BTRFS_EXTENT_DATA_KEY,
+            len,
+        };
         Self { header, item }
     }
-    pub fn parse(&self) -> Result<Option<(ExtentKey, Compression, ExtentStat)>, String> {
+    // fixed size encoding, inline data isn't kept
+    pub fn to_le_bytes(self) -> [u8; SEARCH_ITEM_SIZE] {
+        let (header, item) = (self.header, self.item);
+        let mut ret = [0; SEARCH_ITEM_SIZE];
+        let fields: [&[u8]; 15] = [
+            &header.transid.to_le_bytes(),
+            &header.objectid.to_le_bytes(),
+            &header.offset.to_le_bytes(),
+            &header.r#type.to_le_bytes(),
+            &header.len.to_le_bytes(),
+            &item.generation.to_le_bytes(),
+            &item.ram_bytes.to_le_bytes(),
+            &[item.compression],
+            &[item.encryption],
+            &item.other_encoding.to_le_bytes(),
+            &[item.r#type],
+            &item.disk_bytenr.to_le_bytes(),
+            &item.disk_num_bytes.to_le_bytes(),
+            &item.offset.to_le_bytes(),
+            &item.num_bytes.to_le_bytes(),
+        ];
+        let mut pos = 0;
+        for field in fields {
+            ret[pos..pos + field.len()].copy_from_slice(field);
+            pos += field.len();
+        }
+        ret
+    }
+    pub fn from_le_bytes(buf: &[u8; SEARCH_ITEM_SIZE]) -> Self {
+        let (header, item) = buf.split_at(size_of::<IoctlSearchHeader>());
+        Self {
+            header: IoctlSearchHeader::from_le_bytes(header).unwrap(),
+            item: FileExtentItem::from_le_bytes(item),
+        }
+    }
+    #[cfg(test)]
+    pub fn objectid(&self) -> u64 {
+        self.header.objectid
+    }
+    pub fn parse(&self) -> Result<Extent, String> {
         let hlen = self.header.len;
+        if self.header.r#type != BTRFS_EXTENT_DATA_KEY {
+            return Err(format!("Not a file extent item ({})", {
+                self.header.r#type
+            }));
+        }
         let ram_bytes = self.item.ram_bytes;
-        let comp_type = Compression::from_usize(self.item.compression as _);
-        let extent_type = ExtentType::from_u8(self.item.r#type);
+        let comp_type = Compression::from_u8(self.item.compression)
+            .ok_or_else(|| format!("Unknown compression type ({})", self.item.compression))?;
+        let extent_type = ExtentType::from_u8(self.item.r#type)
+            .ok_or_else(|| format!("Unknown extent type ({})", self.item.r#type))?;
         if extent_type == ExtentType::Inline {
-            const EXTENT_INLINE_HEADER_SIZE: usize = 21;
-            let disk_num_bytes = hlen as u64 - EXTENT_INLINE_HEADER_SIZE as u64;
+            const EXTENT_INLINE_HEADER_SIZE: u32 = 21;
+            let disk_num_bytes = hlen
+                .checked_sub(EXTENT_INLINE_HEADER_SIZE)
+                .ok_or_else(|| format!("Inline extent's header too short ({})", hlen))?
+                as u64;
             // build result
-            return Ok(Some((
-                ExtentKey::new(extent_type, 0),
-                comp_type,
-                ExtentStat {
+            return Ok(Extent {
+                key: ExtentKey::new(extent_type, 0),
+                comp: comp_type,
+                stat: ExtentStat {
                     disk: disk_num_bytes,
                     uncomp: ram_bytes,
                     refd: ram_bytes,
                 },
-            )));
+                offset: 0,
+                pos: self.header.offset,
+                generation: self.item.generation,
+            });
         }
         if hlen != size_of::<FileExtentItem>() as u32 {
             let errmsg = format!("Regular extent's header not 53 bytes ({}) long?!?", hlen,);
             return Err(errmsg);
         }
         let disk_bytenr = self.item.disk_bytenr;
+        let num_bytes = self.item.num_bytes;
+        // the ends of the file range and of the referenced part are used later on
+        let pos = self.header.offset;
+        if pos.checked_add(num_bytes).is_none() || self.item.offset.checked_add(num_bytes).is_none()
+        {
+            return Err(format!("Extent at ({:#x}) ends past 2^64", pos));
+        }
         // is hole
         if disk_bytenr == 0 {
-            return Ok(None);
+            return Ok(Extent {
+                key: ExtentKey::new(ExtentType::Hole, 0),
+                comp: comp_type,
+                stat: ExtentStat {
+                    disk: 0,
+                    uncomp: 0,
+                    refd: num_bytes,
+                },
+                offset: 0,
+                pos: self.header.offset,
+                generation: self.item.generation,
+            });
         }
         // check 4k alignment
         if disk_bytenr & 0xfff != 0 {
@@ -188,16 +449,18 @@ impl IoctlSearchItem {
         }
         let disk_bytenr = disk_bytenr >> 12;
         let disk_num_bytes = self.item.disk_num_bytes;
-        let num_bytes = self.item.num_bytes;
-        Ok(Some((
-            ExtentKey::new(extent_type, disk_bytenr),
-            comp_type,
-            ExtentStat {
+        Ok(Extent {
+            key: ExtentKey::new(extent_type, disk_bytenr),
+            comp: comp_type,
+            stat: ExtentStat {
                 disk: disk_num_bytes,
                 uncomp: ram_bytes,
                 refd: num_bytes,
             },
-        )))
+            offset: self.item.offset,
+            pos: self.header.offset,
+            generation: self.item.generation,
+        })
     }
 }
 
@@ -262,21 +525,353 @@ impl Sv2Args {
     }
 
     fn set_key(&mut self, ino: u64) {
+        let min_transid = self.key.min_transid;
         self.key = IoctlSearchKey::new(ino);
+        self.key.min_transid = min_transid;
+    }
+
+    // skip leaves not written since `transid`, older items in newer leaves are still returned
+    pub fn set_min_transid(&mut self, transid: u64) {
+        self.key.min_transid = transid;
     }
 
-    pub fn search_file(&mut self, fd: File, ino: u64) -> rustix::io::Result<Sv2ItemIter> {
+    // `fd` may be any file or directory in the same subvolume
+    pub fn search_file<'a>(
+        &'a mut self,
+        fd: &'a dyn SearchTarget,
+        ino: u64,
+    ) -> rustix::io::Result<Sv2ItemIter<'a>> {
         self.set_key(ino);
         Sv2ItemIter::new(self, fd)
     }
+
+    // the inodes with items in leaves of the subvolume `fd` lives in that were written in
+    // or after `transid`, including the neighbours sharing those leaves
+    pub fn changed_inodes(&mut self, fd: &File, transid: u64) -> rustix::io::Result<HashSet<u64>> {
+        let saved = self.key;
+        self.key = IoctlSearchKey::new(0);
+        self.key.max_objectid = u64::MAX;
+        self.key.min_type = BTRFS_INODE_ITEM_KEY;
+        self.key.max_type = BTRFS_EXTENT_DATA_KEY;
+        self.key.min_transid = transid;
+        let mut ret = HashSet::new();
+        let found = self.search_tree_from_key(fd, &mut |item| {
+            ret.insert(item.header.objectid);
+            true
+        });
+        self.key = saved;
+        found.map(|()| ret)
+    }
+
+    // every item of tree `tree_id`, 0 for the subvolume `fd` lives in, with a type in
+    // min_type..=max_type, in key order, until `f` returns false
+    pub fn search_tree(
+        &mut self,
+        fd: &File,
+        tree_id: u64,
+        min_type: u32,
+        max_type: u32,
+        mut f: impl FnMut(TreeItem<'_>) -> bool,
+    ) -> rustix::io::Result<()> {
+        let saved = self.key;
+        self.key = IoctlSearchKey::new(0);
+        self.key.tree_id = tree_id;
+        self.key.max_objectid = u64::MAX;
+        self.key.min_type = min_type;
+        self.key.max_type = max_type;
+        let ret = self.search_tree_from_key(fd, &mut f);
+        self.key = saved;
+        ret
+    }
+
+    fn search_tree_from_key(
+        &mut self,
+        fd: &File,
+        f: &mut impl FnMut(TreeItem<'_>) -> bool,
+    ) -> rustix::io::Result<()> {
+        let (min_type, max_type) = (self.key.min_type, self.key.max_type);
+        loop {
+            self.key.nr_items = u32::MAX;
+            self.call_ioctl(fd)?;
+            let mut pos = 0;
+            let mut last = None;
+            for _ in 0..self.key.nr_items {
+                let Some(header) = IoctlSearchHeader::from_le_bytes(&self.buf[pos..]) else {
+                    break;
+                };
+                pos += size_of::<IoctlSearchHeader>();
+                let Some(data) = self.buf.get(pos..pos + header.len as usize) else {
+                    break;
+                };
+                pos += data.len();
+                last = Some(header);
+                // the range is on the whole key, other types in between show up too
+                if (min_type..=max_type).contains(&header.r#type) && !f(TreeItem { header, data }) {
+                    return Ok(());
+                }
+            }
+            let Some(last) = last else {
+                return Ok(());
+            };
+            // continue right after the last key
+            let key = &mut self.key;
+            (key.min_objectid, key.min_type, key.min_offset) =
+                match (last.offset.checked_add(1), last.r#type.checked_add(1)) {
+                    (Some(offset), _) => (last.objectid, last.r#type, offset),
+                    (None, Some(r#type)) => (last.objectid, r#type, 0),
+                    (None, None) => match last.objectid.checked_add(1) {
+                        Some(objectid) => (objectid, 0, 0),
+                        None => return Ok(()),
+                    },
+                };
+        }
+    }
+
+    fn call_ioctl(&mut self, fd: &File) -> Result<(), Errno> {
+        if search_v1() {
+            return self.call_ioctl_v1(fd);
+        }
+        let key = self.key;
+        let ret = unsafe {
+            let ctl = Updater::<'_, ReadWriteOpcode<BTRFS_IOCTL_MAGIC, 17, Sv2Args>, _>::new(self);
+            ioctl(fd, ctl)
+        };
+        match ret {
+            Ok(()) => Ok(()),
+            // report the v2 error if v1 doesn't work either
+            Err(e) => {
+                self.key = key;
+                self.call_ioctl_v1(fd).map_err(|_| e)?;
+                SEARCH_V1.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    // same key, results copied to the start of the v2 buffer
+    fn call_ioctl_v1(&mut self, fd: &File) -> Result<(), Errno> {
+        let mut args = SearchArgsV1 {
+            key: self.key,
+            buf: [0; SEARCH_V1_BUF_SIZE],
+        };
+        unsafe {
+            let ctl = Updater::<'_, ReadWriteOpcode<BTRFS_IOCTL_MAGIC, 17, SearchArgsV1>, _>::new(
+                &mut args,
+            );
+            ioctl(fd, ctl)?;
+        }
+        self.key = args.key;
+        self.buf[..SEARCH_V1_BUF_SIZE].copy_from_slice(&args.buf);
+        Ok(())
+    }
+}
+
+// what a search of the items of one file goes to, the filesystem or a fake tree in tests
+pub trait SearchTarget: Debug {
+    // fill the buffer with the items from the key on, like TREE_SEARCH_V2
+    fn search(&self, args: &mut Sv2Args) -> Result<(), Errno>;
+}
+impl SearchTarget for File {
+    fn search(&self, args: &mut Sv2Args) -> Result<(), Errno> {
+        args.call_ioctl(self)
+    }
+}
+
+// items kept in memory, handed out one buffer at a time, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockTree {
+    // sorted by key
+    pub items: Vec<IoctlSearchItem>,
+    pub nsearch: std::cell::Cell<u32>,
+}
+#[cfg(test)]
+impl SearchTarget for MockTree {
+    fn search(&self, args: &mut Sv2Args) -> Result<(), Errno> {
+        self.nsearch.set(self.nsearch.get() + 1);
+        let key = &args.key;
+        let min = (key.min_objectid, key.min_type, key.min_offset);
+        let max = (key.max_objectid, key.max_type, key.max_offset);
+        let mut pos = 0;
+        let mut nr_items = 0;
+        for item in &self.items {
+            let header = item.header;
+            if !(min..=max).contains(&(header.objectid, header.r#type, header.offset)) {
+                continue;
+            }
+            if nr_items == key.nr_items {
+                break;
+            }
+            let raw = item.to_le_bytes();
+            let hlen = size_of::<IoctlSearchHeader>();
+            let end = pos + hlen + header.len as usize;
+            if end > args.buf_size as usize {
+                break;
+            }
+            args.buf[pos..pos + hlen].copy_from_slice(&raw[..hlen]);
+            // inline data isn't kept, zeros stand in for it
+            let data = raw[hlen..].iter().copied().chain(std::iter::repeat(0));
+            for (dst, src) in args.buf[pos + hlen..end].iter_mut().zip(data) {
+                *dst = src;
+            }
+            pos = end;
+            nr_items += 1;
+        }
+        args.key.nr_items = nr_items;
+        Ok(())
+    }
+}
+
+// set once TREE_SEARCH_V2 failed where TREE_SEARCH worked, then v1 is used by every thread
+static SEARCH_V1: AtomicBool = AtomicBool::new(false);
+
+fn search_v1() -> bool {
+    SEARCH_V1.load(Ordering::Relaxed)
+}
+
+const SEARCH_V1_BUF_SIZE: usize = 4096 - size_of::<IoctlSearchKey>();
+
+#[repr(C)]
+struct SearchArgsV1 {
+    key: IoctlSearchKey,
+    buf: [u8; SEARCH_V1_BUF_SIZE],
+}
+
+// any item found by a tree search
+#[derive(Clone, Copy, Debug)]
+pub struct TreeItem<'a> {
+    header: IoctlSearchHeader,
+    data: &'a [u8],
+}
+impl<'a> TreeItem<'a> {
+    // an item read straight from a tree block
+    pub fn new(objectid: u64, r#type: u32, offset: u64, transid: u64, data: &'a [u8]) -> Self {
+        let header = IoctlSearchHeader {
+            transid,
+            objectid,
+            offset,
+            r#type,
+            len: data.len() as u32,
+        };
+        Self { header, data }
+    }
+    pub fn objectid(&self) -> u64 {
+        self.header.objectid
+    }
+    pub fn r#type(&self) -> u32 {
+        self.header.r#type
+    }
+    pub fn offset(&self) -> u64 {
+        self.header.offset
+    }
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+    // None if the item is too short for an inode item
+    pub fn inode(&self) -> Option<InodeItem> {
+        Some(InodeItem {
+            size: le_at(self.data, 16, 8)?,
+            nlink: le_at(self.data, 40, 4)? as u32,
+            mode: le_at(self.data, 52, 4)? as u32,
+        })
+    }
+    // parent directory and name of the first link in an inode ref item
+    pub fn inode_ref(&self) -> Option<(u64, &[u8])> {
+        let len = le_at(self.data, 8, 2)? as usize;
+        Some((self.header.offset, self.data.get(10..10 + len)?))
+    }
+    // where the tree of a subvolume starts, in a root item
+    pub fn root_item(&self) -> Option<RootItem> {
+        Some(RootItem {
+            bytenr: le_at(self.data, 176, 8)?,
+            refs: le_at(self.data, 216, 4)? as u32,
+            level: le_at(self.data, 238, 1)? as u8,
+        })
+    }
+    // directory and name a subvolume is linked under, the key offset is the subvolume
+    pub fn root_ref(&self) -> Option<(u64, &'a [u8])> {
+        let len = le_at(self.data, 16, 2)? as usize;
+        Some((le_at(self.data, 0, 8)?, self.data.get(18..18 + len)?))
+    }
+    // inline data is cut off, short items are zero padded
+    pub fn extent_item(&self) -> IoctlSearchItem {
+        IoctlSearchItem {
+            header: self.header,
+            item: FileExtentItem::from_le_bytes(self.data),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InodeItem {
+    pub size: u64,
+    pub nlink: u32,
+    pub mode: u32,
+}
+impl InodeItem {
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RootItem {
+    pub bytenr: u64,
+    // 0 once the subvolume is deleted and waits for cleanup
+    pub refs: u32,
+    pub level: u8,
+}
+
+// the item at `pos` of a search result and where the next one starts
+fn item_at(buf: &[u8], pos: usize) -> Option<(IoctlSearchItem, usize)> {
+    let header = IoctlSearchHeader::from_le_bytes(buf.get(pos..)?)?;
+    let start = pos + size_of::<IoctlSearchHeader>();
+    let data = buf.get(start..start + header.len as usize)?;
+    Some((TreeItem { header, data }.extent_item(), start + data.len()))
 }
+
+// the used part of the buffer one search ioctl filled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawBatch {
+    pub nr_items: u32,
+    pub buf: Vec<u8>,
+}
+impl RawBatch {
+    fn new(buf: &[u8], nr_items: u32) -> Self {
+        let mut len = 0;
+        for _ in 0..nr_items {
+            match item_at(buf, len) {
+                Some((_, next)) => len = next,
+                None => break,
+            }
+        }
+        Self {
+            nr_items,
+            buf: buf[..len].to_vec(),
+        }
+    }
+    pub fn items(&self) -> impl Iterator<Item = IoctlSearchItem> + '_ {
+        let mut pos = 0;
+        (0..self.nr_items).map_while(move |_| {
+            let (item, next) = item_at(&self.buf, pos)?;
+            pos = next;
+            Some(item)
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Sv2ItemIter<'arg> {
     sv2_arg: &'arg mut Sv2Args,
-    fd: File,
+    fd: &'arg dyn SearchTarget,
     pos: usize,
     nrest_item: u32,
     last: bool,
+    // every buffer, when recording
+    batches: Option<Vec<RawBatch>>,
 }
 impl Iterator for Sv2ItemIter<'_> {
     type Item = IoctlSearchItem;
@@ -288,8 +883,13 @@ impl Iterator for Sv2ItemIter<'_> {
         if self.finish() {
             return None;
         }
-        let ret = unsafe { IoctlSearchItem::from_le_raw(&self.sv2_arg.buf[self.pos..]) };
-        self.pos += size_of::<IoctlSearchHeader>() + ret.header.len as usize;
+        let Some((ret, next)) = item_at(&self.sv2_arg.buf, self.pos) else {
+            // cut off, nothing sane follows
+            self.nrest_item = 0;
+            self.last = true;
+            return None;
+        };
+        self.pos = next;
         self.nrest_item -= 1;
         if self.nrest_item == 0 {
             self.sv2_arg.key.min_offset = ret.header.offset + 1;
@@ -300,14 +900,17 @@ impl Iterator for Sv2ItemIter<'_> {
 impl FusedIterator for Sv2ItemIter<'_> {}
 impl<'arg> Sv2ItemIter<'arg> {
     fn call_ioctl(&mut self) -> Result<(), Errno> {
-        unsafe {
-            let ctl = Updater::<'_, ReadWriteOpcode<BTRFS_IOCTL_MAGIC, 17, Sv2Args>, _>::new(
-                self.sv2_arg,
-            );
-            ioctl(&self.fd, ctl)?;
-        }
+        self.fd.search(self.sv2_arg)?;
         self.nrest_item = self.sv2_arg.key.nr_items;
-        self.last = self.nrest_item <= 512;
+        if let Some(batches) = &mut self.batches {
+            batches.push(RawBatch::new(&self.sv2_arg.buf, self.nrest_item));
+        }
+        // v1 fills its small buffer without telling whether more items are left
+        self.last = if search_v1() {
+            self.nrest_item == 0
+        } else {
+            self.nrest_item <= 512
+        };
         self.pos = 0;
         Ok(())
     }
@@ -317,7 +920,7 @@ impl<'arg> Sv2ItemIter<'arg> {
     fn finish(&self) -> bool {
         self.nrest_item == 0 && self.last
     }
-    pub fn new(sv2_arg: &'arg mut Sv2Args, fd: File) -> Result<Self, Errno> {
+    pub fn new(sv2_arg: &'arg mut Sv2Args, fd: &'arg dyn SearchTarget) -> Result<Self, Errno> {
         sv2_arg.key.nr_items = u32::MAX;
         sv2_arg.key.min_offset = 0;
         // other fields not reset, maybe error?
@@ -327,8 +930,18 @@ impl<'arg> Sv2ItemIter<'arg> {
             pos: 0,
             nrest_item: 0,
             last: false,
+            batches: None,
         };
         ret.call_ioctl()?;
         Ok(ret)
     }
+    // keep the raw buffers, from the first one on
+    pub fn record(mut self) -> Self {
+        let first = RawBatch::new(&self.sv2_arg.buf, self.nrest_item);
+        self.batches = Some(vec![first]);
+        self
+    }
+    pub fn into_batches(self) -> Vec<RawBatch> {
+        self.batches.unwrap_or_default()
+    }
 }
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+
+use crate::{
+    btrfs::{Fsid, IoctlSearchItem, SEARCH_ITEM_SIZE},
+    Location,
+};
+
+const MAGIC: &[u8; 8] = b"CSRSCACH";
+const VERSION: u32 = 3;
+// fsid, subvol, cached at
+const SUBVOL_SIZE: usize = 16 + 8 * 2;
+// fsid, subvol, ino, item count
+const RECORD_HEADER_SIZE: usize = 16 + 8 * 2 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub loc: Location,
+    pub ino: u64,
+}
+
+// the raw extent items of every file, by subvolume and inode
+#[derive(Debug, Default)]
+pub struct Cache {
+    // filesystem generation of every subvolume before its files were read; the leaves
+    // written since then hold every inode that changed, or whose extents balance moved
+    pub generations: HashMap<Location, u64>,
+    pub files: HashMap<CacheKey, Vec<IoctlSearchItem>>,
+}
+impl Cache {
+    pub fn merge(&mut self, rhs: Self) {
+        for (loc, generation) in rhs.generations {
+            self.generations
+                .entry(loc)
+                .and_modify(|g| *g = (*g).min(generation))
+                .or_insert(generation);
+        }
+        self.files.extend(rhs.files);
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Cache> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Cache::default()),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "Invalid cache file");
+    if buf.len() < 12 || &buf[..8] != MAGIC {
+        return Err(invalid());
+    }
+    if u32::from_le_bytes(buf[8..12].try_into().unwrap()) != VERSION {
+        // written by another version, start over
+        return Ok(Cache::default());
+    }
+    let mut ret = Cache::default();
+    let mut rest = &buf[12..];
+    let location = |rest: &[u8]| Location {
+        fsid: Fsid(rest[..16].try_into().unwrap()),
+        subvol: u64::from_le_bytes(rest[16..24].try_into().unwrap()),
+    };
+    let nsubvol = rest.get(..4).ok_or_else(invalid)?;
+    let nsubvol = u32::from_le_bytes(nsubvol.try_into().unwrap()) as usize;
+    rest = &rest[4..];
+    for _ in 0..nsubvol {
+        if rest.len() < SUBVOL_SIZE {
+            return Err(invalid());
+        }
+        let generation = u64::from_le_bytes(rest[24..32].try_into().unwrap());
+        ret.generations.insert(location(rest), generation);
+        rest = &rest[SUBVOL_SIZE..];
+    }
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_SIZE {
+            return Err(invalid());
+        }
+        let key = CacheKey {
+            loc: location(rest),
+            ino: u64::from_le_bytes(rest[24..32].try_into().unwrap()),
+        };
+        // files are only valid since the generation of their subvolume
+        if !ret.generations.contains_key(&key.loc) {
+            return Err(invalid());
+        }
+        let nitem = u32::from_le_bytes(rest[32..36].try_into().unwrap()) as usize;
+        rest = &rest[RECORD_HEADER_SIZE..];
+        let len = nitem.checked_mul(SEARCH_ITEM_SIZE).ok_or_else(invalid)?;
+        if rest.len() < len {
+            return Err(invalid());
+        }
+        let items = rest[..len]
+            .chunks_exact(SEARCH_ITEM_SIZE)
+            .map(|raw| IoctlSearchItem::from_le_bytes(raw.try_into().unwrap()))
+            .collect();
+        rest = &rest[len..];
+        ret.files.insert(key, items);
+    }
+    Ok(ret)
+}
+
+pub fn save(path: &Path, cache: &Cache) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut w = BufWriter::new(fs::File::create(&tmp)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(cache.generations.len() as u32).to_le_bytes())?;
+    for (loc, generation) in &cache.generations {
+        w.write_all(&loc.fsid.0)?;
+        w.write_all(&loc.subvol.to_le_bytes())?;
+        w.write_all(&generation.to_le_bytes())?;
+    }
+    for (key, items) in &cache.files {
+        w.write_all(&key.loc.fsid.0)?;
+        w.write_all(&key.loc.subvol.to_le_bytes())?;
+        w.write_all(&key.ino.to_le_bytes())?;
+        w.write_all(&(items.len() as u32).to_le_bytes())?;
+        for item in items {
+            w.write_all(&item.to_le_bytes())?;
+        }
+    }
+    w.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,131 @@
+use std::{cmp::Reverse, fmt::Display, path::PathBuf};
+
+use crate::{kind_name, scale::Scale, ExtentMap, ExtentStat, FileExtents, NKIND};
+
+const ONLY_A: usize = 0;
+const ONLY_B: usize = 1;
+const SHARED: usize = 2;
+
+// per compression type (and prealloc), the extents only in A, only in B, and in both
+#[derive(Debug, Default)]
+pub struct DiffStat {
+    sides: [[ExtentStat; NKIND]; 3],
+}
+
+impl DiffStat {
+    pub fn collect(extent_map: &ExtentMap) -> Self {
+        let mut ret = Self::default();
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            let side = match refs.groups.single() {
+                Some(0) => ONLY_A,
+                Some(_) => ONLY_B,
+                None => SHARED,
+            };
+            ret.sides[side][refs.kind()].merge(ExtentStat {
+                disk: refs.disk,
+                uncomp: refs.uncomp,
+                refd: 0,
+            });
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> DiffStatDisplay<'_> {
+        DiffStatDisplay { stat: self, scale }
+    }
+}
+
+type StatField = fn(&ExtentStat) -> u64;
+
+pub struct DiffStatDisplay<'a> {
+    stat: &'a DiffStat,
+    scale: Scale,
+}
+impl Display for DiffStatDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { stat, scale } = self;
+        let tables: [(&str, StatField); 2] =
+            [("Disk Usage", |s| s.disk), ("Uncompressed", |s| s.uncomp)];
+        for (i, (title, field)) in tables.into_iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            writeln!(
+                f,
+                "{:10} {:12} {:12} {:12}",
+                title, "Only in A", "Only in B", "Shared"
+            )?;
+            let total = |side: usize| stat.sides[side].iter().map(field).sum::<u64>();
+            writeln!(
+                f,
+                "{:10} {:12} {:12} {:12}",
+                "TOTAL",
+                scale.scale(total(ONLY_A)),
+                scale.scale(total(ONLY_B)),
+                scale.scale(total(SHARED))
+            )?;
+            for kind in 0..NKIND {
+                if stat.sides.iter().all(|side| side[kind].is_empty()) {
+                    continue;
+                }
+                writeln!(
+                    f,
+                    "{:10} {:12} {:12} {:12}",
+                    kind_name(kind),
+                    scale.scale(field(&stat.sides[ONLY_A][kind])),
+                    scale.scale(field(&stat.sides[ONLY_B][kind])),
+                    scale.scale(field(&stat.sides[SHARED][kind]))
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// for both sides, the files with most disk bytes that aren't on the other side
+pub fn top_files(
+    extent_map: &ExtentMap,
+    files: Vec<FileExtents>,
+    n: usize,
+) -> [Vec<(PathBuf, u64)>; 2] {
+    let mut ret: [Vec<_>; 2] = Default::default();
+    for mut file in files {
+        file.extents.sort_unstable_by_key(|id| id.bytenr);
+        file.extents.dedup();
+        let unique = file
+            .extents
+            .iter()
+            .filter_map(|id| {
+                let refs = extent_map.get(id)?;
+                let refs = refs.as_deref()?;
+                (refs.groups.single() == Some(file.group)).then_some(refs.disk)
+            })
+            .sum::<u64>();
+        if unique != 0 {
+            ret[file.group as usize].push((file.path, unique));
+        }
+    }
+    for side in &mut ret {
+        side.sort_unstable_by_key(|(_, bytes)| Reverse(*bytes));
+        side.truncate(n);
+    }
+    ret
+}
+
+pub struct TopFilesDisplay<'a> {
+    pub top: &'a [Vec<(PathBuf, u64)>; 2],
+    pub scale: Scale,
+}
+impl Display for TopFilesDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, side) in ["A", "B"].iter().zip(self.top.iter()) {
+            writeln!(f, "Top files only in {}:", name)?;
+            for (path, bytes) in side {
+                writeln!(f, "{:>12}  {}", self.scale.scale(*bytes), path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt::Display;
+
+use crate::{scale::Scale, ExtentMap};
+
+// disk usage of one argument, like `btrfs filesystem du`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DuStat {
+    pub total: u64,
+    pub exclusive: u64,
+}
+impl DuStat {
+    pub fn shared(&self) -> u64 {
+        self.total - self.exclusive
+    }
+}
+
+// `inline` holds the bytes of inline extents per argument, they are never shared
+pub fn collect(extent_map: &ExtentMap, inline: &[u64]) -> Vec<DuStat> {
+    let mut ret: Vec<_> = inline
+        .iter()
+        .map(|&n| DuStat {
+            total: n,
+            exclusive: n,
+        })
+        .collect();
+    for entry in extent_map.iter() {
+        let Some(refs) = entry.value().as_deref() else {
+            continue;
+        };
+        for group in refs.groups.iter() {
+            ret[group as usize].total += refs.disk;
+        }
+        if let Some(group) = refs.groups.single() {
+            ret[group as usize].exclusive += refs.disk;
+        }
+    }
+    ret
+}
+
+pub struct DuDisplay<'a> {
+    pub paths: &'a [String],
+    pub stats: &'a [DuStat],
+    pub scale: Scale,
+}
+impl Display for DuDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            paths,
+            stats,
+            scale,
+        } = self;
+        writeln!(
+            f,
+            "{:>12} {:>12} {:>12}  Path",
+            "Total", "Exclusive", "Shared"
+        )?;
+        for (path, stat) in paths.iter().zip(stats.iter()) {
+            writeln!(
+                f,
+                "{:>12} {:>12} {:>12}  {}",
+                scale.scale(stat.total),
+                scale.scale(stat.exclusive),
+                scale.scale(stat.shared()),
+                path
+            )?;
+        }
+        Ok(())
+    }
+}
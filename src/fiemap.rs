@@ -0,0 +1,131 @@
+use std::{fs::File, io, path::Path};
+
+use rustix::ioctl::{ioctl, ReadWriteOpcode, Updater};
+
+use crate::{
+    btrfs::{Compression, Extent, ExtentKey, ExtentType},
+    ExtentStat,
+};
+
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+
+// compressed extents never hold more than this, on disk or not
+const MAX_COMPRESSED: u64 = 128 * 1024;
+const NEXTENT: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct FiemapHeader {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapArgs {
+    header: FiemapHeader,
+    extents: [FiemapExtent; NEXTENT],
+}
+
+// all mapped extents of the file in logical order, delalloc left out
+fn fiemap(file: &File) -> rustix::io::Result<Vec<FiemapExtent>> {
+    let mut ret = Vec::new();
+    let mut args = FiemapArgs {
+        header: FiemapHeader::default(),
+        extents: [FiemapExtent::default(); NEXTENT],
+    };
+    loop {
+        args.header = FiemapHeader {
+            start: ret
+                .last()
+                .map_or(0, |e: &FiemapExtent| e.logical + e.length),
+            length: u64::MAX,
+            extent_count: NEXTENT as u32,
+            ..Default::default()
+        };
+        unsafe {
+            // the opcode carries the size of the header only
+            let ctl = Updater::<'_, ReadWriteOpcode<b'f', 11, FiemapHeader>, _>::new(&mut args);
+            ioctl(file, ctl)?;
+        }
+        let mapped = &args.extents[..args.header.mapped_extents as usize];
+        ret.extend_from_slice(mapped);
+        match mapped.last() {
+            Some(e) if e.flags & FIEMAP_EXTENT_LAST == 0 => {}
+            _ => break,
+        }
+    }
+    ret.retain(|e| e.flags & (FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_UNKNOWN) == 0);
+    Ok(ret)
+}
+
+// FIEMAP only tells the logical length and where the data starts on disk: the disk size
+// of an encoded extent is at most the distance to the next extent of the file, its
+// uncompressed size is the part the file references, and the referenced part of an
+// uncompressed extent is taken as an extent of its own. Inline extents are reported
+// a whole sector long, but never hold more than the file, which is `size` long.
+// FIEMAP_EXTENT_SHARED is left alone, sharing is only ever counted between the
+// scanned files, as with the tree search
+pub fn read(path: &Path, size: u64, out: &mut Vec<Extent>) -> io::Result<()> {
+    let file = File::open(path)?;
+    let extents = fiemap(&file)?;
+    for (i, e) in extents.iter().enumerate() {
+        let inline = e.flags & FIEMAP_EXTENT_DATA_INLINE != 0;
+        let length = if inline { e.length.min(size) } else { e.length };
+        let comp = if e.flags & FIEMAP_EXTENT_ENCODED != 0 {
+            Compression::Encoded
+        } else {
+            Compression::None
+        };
+        let r#type = if inline {
+            ExtentType::Inline
+        } else if e.flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+            ExtentType::Prealloc
+        } else {
+            ExtentType::Regular
+        };
+        let disk = if comp == Compression::Encoded {
+            let upper = length.min(MAX_COMPRESSED);
+            match extents.get(i + 1) {
+                Some(next) if next.physical > e.physical && !inline => {
+                    (next.physical - e.physical).min(upper)
+                }
+                _ => upper,
+            }
+        } else {
+            length
+        };
+        out.push(Extent {
+            key: ExtentKey::new(r#type, if inline { 0 } else { e.physical >> 12 }),
+            comp,
+            stat: ExtentStat {
+                disk,
+                uncomp: length,
+                refd: length,
+            },
+            offset: 0,
+            pos: e.logical,
+            generation: 0,
+        });
+    }
+    Ok(())
+}
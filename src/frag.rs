@@ -0,0 +1,119 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::{
+    btrfs::{Compression, Extent},
+    scale::Scale,
+};
+
+const MIB: u64 = 1024 * 1024;
+
+// fragmentation of one file or a whole tree
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FragStat {
+    pub nfile: u64,
+    pub nextent: u64,
+    // physically contiguous runs of extents
+    pub nrun: u64,
+    pub bytes: u64,
+}
+impl FragStat {
+    pub fn merge(&mut self, rhs: Self) {
+        self.nfile += rhs.nfile;
+        self.nextent += rhs.nextent;
+        self.nrun += rhs.nrun;
+        self.bytes += rhs.bytes;
+    }
+    fn extents_per_mib(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
+        }
+        self.nextent as f64 * MIB as f64 / self.bytes as f64
+    }
+    fn avg_extent(&self) -> u64 {
+        self.bytes.checked_div(self.nextent).unwrap_or(0)
+    }
+}
+
+// feed the extents of one file in file offset order
+#[derive(Debug, Default)]
+pub struct FileFrag {
+    stat: FragStat,
+    last_end: Option<u64>,
+}
+impl FileFrag {
+    pub fn add(&mut self, ext: &Extent) {
+        if !ext.key.r#type().has_extent() {
+            return;
+        }
+        let bytenr = ext.key.key() << 12;
+        // only uncompressed extents can be entered in the middle
+        let (start, end) = if ext.comp == Compression::None {
+            let start = bytenr + ext.offset;
+            (start, start + ext.stat.refd)
+        } else {
+            (bytenr, bytenr + ext.stat.disk)
+        };
+        if self.last_end != Some(start) {
+            self.stat.nrun += 1;
+        }
+        self.last_end = Some(end);
+        self.stat.nextent += 1;
+        self.stat.bytes += ext.stat.refd;
+    }
+    pub fn finish(mut self) -> FragStat {
+        self.stat.nfile = 1;
+        self.stat
+    }
+}
+
+pub struct FragDisplay<'a> {
+    pub paths: &'a [String],
+    pub stats: &'a [FragStat],
+    pub top: &'a [(PathBuf, FragStat)],
+    pub scale: Scale,
+}
+impl Display for FragDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write_row(
+            f: &mut std::fmt::Formatter<'_>,
+            stat: &FragStat,
+            scale: Scale,
+            name: impl Display,
+        ) -> std::fmt::Result {
+            writeln!(
+                f,
+                "{:>10} {:>10} {:>12} {:>10.2} {:>12}  {}",
+                stat.nextent,
+                stat.nrun,
+                scale.scale(stat.bytes),
+                stat.extents_per_mib(),
+                scale.scale(stat.avg_extent()),
+                name
+            )
+        }
+        let Self {
+            paths,
+            stats,
+            top,
+            scale,
+        } = self;
+        writeln!(
+            f,
+            "{:>10} {:>10} {:>12} {:>10} {:>12}  Path",
+            "Extents", "Runs", "Size", "Ext/MiB", "Avg Extent"
+        )?;
+        let mut total = FragStat::default();
+        for (path, stat) in paths.iter().zip(stats.iter()) {
+            total.merge(*stat);
+            write_row(f, stat, *scale, path)?;
+        }
+        if paths.len() > 1 {
+            write_row(f, &total, *scale, "TOTAL")?;
+        }
+        writeln!(f, "\nMost fragmented files:")?;
+        for (path, stat) in top.iter() {
+            write_row(f, stat, *scale, path.display())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt::Display;
+
+use crate::{
+    btrfs::{Compression, NCOMP},
+    kind_name,
+    scale::Scale,
+    ExtentMap, ExtentStat, NKIND,
+};
+
+// upper bounds (inclusive) of the reference count buckets, the last one is open
+const REF_BUCKETS: [u32; 5] = [1, 2, 10, 100, 1000];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RefBucket {
+    nextent: u64,
+    disk: u64,
+    refd: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RefHistogram {
+    buckets: [RefBucket; REF_BUCKETS.len() + 1],
+}
+
+impl RefHistogram {
+    pub fn collect(extent_map: &ExtentMap) -> Self {
+        let mut ret = Self::default();
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            let i = REF_BUCKETS
+                .iter()
+                .position(|&max| refs.nref <= max)
+                .unwrap_or(REF_BUCKETS.len());
+            let bucket = &mut ret.buckets[i];
+            bucket.nextent += 1;
+            bucket.disk += refs.disk;
+            bucket.refd += refs.refd;
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> RefHistogramDisplay<'_> {
+        RefHistogramDisplay { hist: self, scale }
+    }
+}
+
+pub struct RefHistogramDisplay<'a> {
+    hist: &'a RefHistogram,
+    scale: Scale,
+}
+impl Display for RefHistogramDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { hist, scale } = self;
+        writeln!(
+            f,
+            "{:10} {:12} {:12} {:12}",
+            "Refs", "Extents", "Disk Usage", "Referenced"
+        )?;
+        let mut min = 1;
+        for (i, bucket) in hist.buckets.iter().enumerate() {
+            let range = match REF_BUCKETS.get(i) {
+                Some(&max) if max == min => format!("{}", min),
+                Some(&max) => format!("{}-{}", min, max),
+                None => format!("{}+", min),
+            };
+            if let Some(&max) = REF_BUCKETS.get(i) {
+                min = max + 1;
+            }
+            if bucket.nextent == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "{:10} {:12} {:12} {:12}",
+                range,
+                bucket.nextent,
+                scale.scale(bucket.disk),
+                scale.scale(bucket.refd)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+const DISK: usize = 0;
+const UNCOMP: usize = 1;
+
+// log2 buckets of extent sizes, on disk and uncompressed, per extent kind
+#[derive(Debug)]
+pub struct SizeHistogram {
+    counts: [[[u64; 64]; 2]; NKIND],
+}
+
+impl SizeHistogram {
+    pub fn collect(extent_map: &ExtentMap) -> Self {
+        let mut ret = Self {
+            counts: [[[0; 64]; 2]; NKIND],
+        };
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            let counts = &mut ret.counts[refs.kind()];
+            for (i, size) in [(DISK, refs.disk), (UNCOMP, refs.uncomp)] {
+                if size != 0 {
+                    counts[i][size.ilog2() as usize] += 1;
+                }
+            }
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> SizeHistogramDisplay<'_> {
+        SizeHistogramDisplay { hist: self, scale }
+    }
+}
+
+pub struct SizeHistogramDisplay<'a> {
+    hist: &'a SizeHistogram,
+    scale: Scale,
+}
+impl Display for SizeHistogramDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { hist, scale } = self;
+        for (kind, counts) in hist.counts.iter().enumerate() {
+            let used = |i: usize| counts[DISK][i] != 0 || counts[UNCOMP][i] != 0;
+            let Some(first) = (0..64).find(|&i| used(i)) else {
+                continue;
+            };
+            let last = (0..64).rfind(|&i| used(i)).unwrap();
+            writeln!(f, "{} extents by size:", kind_name(kind))?;
+            writeln!(f, "{:>12} {:>12} {:>12}", ">= Size", "Disk", "Uncompressed")?;
+            let rows = counts[DISK].iter().zip(&counts[UNCOMP]).enumerate();
+            for (i, (disk, uncomp)) in rows.take(last + 1).skip(first) {
+                writeln!(f, "{:>12} {:>12} {:>12}", scale.scale(1 << i), disk, uncomp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 10% wide buckets of disk/uncompressed, the last one for extents that grew
+const RATIO_BUCKETS: usize = 11;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RatioBucket {
+    nextent: u64,
+    stat: ExtentStat,
+}
+
+// per compression algorithm, the distribution of per extent compression ratios
+#[derive(Debug, Default)]
+pub struct RatioHistogram {
+    buckets: [[RatioBucket; RATIO_BUCKETS]; NCOMP],
+}
+
+impl RatioHistogram {
+    pub fn collect(extent_map: &ExtentMap) -> Self {
+        let mut ret = Self::default();
+        for entry in extent_map.iter() {
+            let Some(refs) = entry.value().as_deref() else {
+                continue;
+            };
+            if refs.comp == Compression::None || refs.uncomp == 0 {
+                continue;
+            }
+            let i = ((refs.disk * 10 / refs.uncomp) as usize).min(RATIO_BUCKETS - 1);
+            let bucket = &mut ret.buckets[refs.comp.as_usize()][i];
+            bucket.nextent += 1;
+            bucket.stat.merge(ExtentStat {
+                disk: refs.disk,
+                uncomp: refs.uncomp,
+                refd: 0,
+            });
+        }
+        ret
+    }
+    pub fn display(&self, scale: Scale) -> RatioHistogramDisplay<'_> {
+        RatioHistogramDisplay { hist: self, scale }
+    }
+}
+
+pub struct RatioHistogramDisplay<'a> {
+    hist: &'a RatioHistogram,
+    scale: Scale,
+}
+impl Display for RatioHistogramDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { hist, scale } = self;
+        for (comp, buckets) in hist.buckets.iter().enumerate() {
+            let total: u64 = buckets.iter().map(|b| b.stat.uncomp).sum();
+            if total == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "{} compression ratio:",
+                Compression::from_usize(comp).name()
+            )?;
+            writeln!(
+                f,
+                "{:10} {:12} {:12} {:12} {:>6}",
+                "Perc", "Extents", "Disk Usage", "Uncompressed", "Share"
+            )?;
+            for (i, bucket) in buckets.iter().enumerate() {
+                if bucket.nextent == 0 {
+                    continue;
+                }
+                let range = if i == RATIO_BUCKETS - 1 {
+                    ">=100%".to_string()
+                } else {
+                    format!("{}-{}%", i * 10, i * 10 + 10)
+                };
+                writeln!(
+                    f,
+                    "{:10} {:12} {:12} {:12} {:>5.1}%",
+                    range,
+                    bucket.nextent,
+                    scale.scale(bucket.stat.disk),
+                    scale.scale(bucket.stat.uncomp),
+                    bucket.stat.uncomp as f64 * 100.0 / total as f64
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs::File,
+    io::{self, ErrorKind},
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    btrfs::{
+        le_at, Fsid, RootItem, TreeItem, BTRFS_CHUNK_ITEM_KEY, BTRFS_FIRST_FREE_OBJECTID,
+        BTRFS_INODE_ITEM_KEY, BTRFS_INODE_REF_KEY,
+    },
+    tree::{RootTree, Subvolume},
+    Location, WalkInfo, WorkerTx,
+};
+
+const SUPER_OFFSET: u64 = 64 * 1024;
+const SUPER_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+const SYS_CHUNK_ARRAY: usize = 0x32b;
+const SYS_CHUNK_ARRAY_MAX: usize = 2048;
+const INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+// raid0, raid10, raid5, raid6: no device holds a whole copy of the chunk
+const STRIPED: u64 = 1 << 3 | 1 << 6 | 1 << 7 | 1 << 8;
+const HEADER_SIZE: usize = 101;
+const ITEM_SIZE: usize = 25;
+const KEY_PTR_SIZE: usize = 33;
+const MAX_LEVEL: u8 = 7;
+const CHUNK_ITEM_SIZE: usize = 48;
+const STRIPE_SIZE: usize = 32;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// objectid, type and offset
+fn key_at(buf: &[u8], at: usize) -> Option<(u64, u32, u64)> {
+    Some((
+        le_at(buf, at, 8)?,
+        le_at(buf, at + 8, 1)? as u32,
+        le_at(buf, at + 9, 8)?,
+    ))
+}
+
+// a range of logical addresses and where this device keeps a copy of it
+struct Chunk {
+    len: u64,
+    physical: Option<u64>,
+}
+
+// one device of a filesystem, read through the tree blocks, never written
+struct Image {
+    file: File,
+    fsid: Fsid,
+    // what tree blocks carry, differs from the fsid once that was changed
+    metadata_uuid: [u8; 16],
+    generation: u64,
+    nodesize: usize,
+    devid: u64,
+    chunks: BTreeMap<u64, Chunk>,
+}
+impl Image {
+    // returns the root tree as well
+    fn open(path: &Path) -> io::Result<(Self, RootItem)> {
+        let file = File::open(path)?;
+        let mut sb = vec![0; SUPER_SIZE];
+        file.read_exact_at(&mut sb, SUPER_OFFSET)
+            .map_err(|_| invalid("Not btrfs, no superblock".to_string()))?;
+        if &sb[0x40..0x48] != MAGIC {
+            return Err(invalid("Not btrfs, no superblock".to_string()));
+        }
+        let field = |at: usize, n: usize| le_at(&sb, at, n).unwrap();
+        let fsid: [u8; 16] = sb[0x20..0x30].try_into().unwrap();
+        let metadata_uuid = if field(0xbc, 8) & INCOMPAT_METADATA_UUID != 0 {
+            sb[0x23b..0x24b].try_into().unwrap()
+        } else {
+            fsid
+        };
+        let nodesize = field(0x94, 4) as usize;
+        if !nodesize.is_power_of_two() || !(4096..=65536).contains(&nodesize) {
+            return Err(invalid(format!("Invalid node size {}", nodesize)));
+        }
+        let mut image = Self {
+            file,
+            fsid: Fsid(fsid),
+            metadata_uuid,
+            generation: field(0x48, 8),
+            nodesize,
+            devid: field(0xc9, 8),
+            chunks: BTreeMap::new(),
+        };
+        // enough of the chunk tree to read the chunk tree
+        let len = (field(0xa0, 4) as usize).min(SYS_CHUNK_ARRAY_MAX);
+        let array = &sb[SYS_CHUNK_ARRAY..SYS_CHUNK_ARRAY + len];
+        let mut pos = 0;
+        while pos < array.len() {
+            let (_, r#type, logical) =
+                key_at(array, pos).ok_or_else(|| invalid("Bad system chunk array".to_string()))?;
+            let data = array.get(pos + 17..).unwrap_or_default();
+            if r#type != BTRFS_CHUNK_ITEM_KEY {
+                return Err(invalid("Bad system chunk array".to_string()));
+            }
+            pos += 17 + image.add_chunk(logical, data)?;
+        }
+        let chunk_root = RootItem {
+            bytenr: field(0x58, 8),
+            refs: 1,
+            level: field(0xc7, 1) as u8,
+        };
+        let mut chunks = Vec::new();
+        image.walk_tree(chunk_root, &mut |item| {
+            if item.r#type() == BTRFS_CHUNK_ITEM_KEY {
+                chunks.push((item.offset(), item.data().to_owned()));
+            }
+            true
+        })?;
+        for (logical, data) in chunks {
+            image.add_chunk(logical, &data)?;
+        }
+        let root = RootItem {
+            bytenr: field(0x50, 8),
+            refs: 1,
+            level: field(0xc6, 1) as u8,
+        };
+        Ok((image, root))
+    }
+
+    // returns the size of the chunk item
+    fn add_chunk(&mut self, logical: u64, data: &[u8]) -> io::Result<usize> {
+        let bad = || invalid(format!("Bad chunk item at {:#x}", logical));
+        let len = le_at(data, 0, 8).ok_or_else(bad)?;
+        let profile = le_at(data, 24, 8).ok_or_else(bad)?;
+        let nstripes = le_at(data, 44, 2).ok_or_else(bad)? as usize;
+        let size = CHUNK_ITEM_SIZE + nstripes * STRIPE_SIZE;
+        let stripes = data.get(CHUNK_ITEM_SIZE..size).ok_or_else(bad)?;
+        let physical = stripes
+            .chunks(STRIPE_SIZE)
+            .find(|stripe| le_at(stripe, 0, 8) == Some(self.devid))
+            .and_then(|stripe| le_at(stripe, 8, 8))
+            .filter(|_| profile & STRIPED == 0);
+        self.chunks.insert(logical, Chunk { len, physical });
+        Ok(size)
+    }
+
+    fn read_block(&self, logical: u64) -> io::Result<Vec<u8>> {
+        let physical = match self.chunks.range(..=logical).next_back() {
+            Some((start, chunk)) if logical - start < chunk.len => chunk
+                .physical
+                .map(|physical| physical + (logical - start))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Tree block {:#x} is striped or on another device",
+                        logical
+                    ))
+                })?,
+            _ => {
+                return Err(invalid(format!(
+                    "Tree block {:#x} not in any chunk",
+                    logical
+                )))
+            }
+        };
+        let mut block = vec![0; self.nodesize];
+        self.file.read_exact_at(&mut block, physical)?;
+        // no checksums, but a block of some other tree or filesystem shows here
+        if le_at(&block, 0x30, 8) != Some(logical) || block[0x20..0x30] != self.metadata_uuid {
+            return Err(invalid(format!("Bad tree block at {:#x}", logical)));
+        }
+        Ok(block)
+    }
+
+    // every leaf item in key order until `f` returns false, which is returned
+    fn walk_tree(&self, root: RootItem, f: &mut dyn FnMut(TreeItem) -> bool) -> io::Result<bool> {
+        let block = self.read_block(root.bytenr)?;
+        let level = block[100];
+        if level != root.level || level > MAX_LEVEL {
+            return Err(invalid(format!(
+                "Tree block {:#x} at wrong level {}",
+                root.bytenr, level
+            )));
+        }
+        let transid = le_at(&block, 0x50, 8).unwrap();
+        let nritems = le_at(&block, 0x60, 4).unwrap() as usize;
+        let bad = || invalid(format!("Bad tree block at {:#x}", root.bytenr));
+        for i in 0..nritems {
+            let go_on = if level == 0 {
+                let at = HEADER_SIZE + i * ITEM_SIZE;
+                let (objectid, r#type, offset) = key_at(&block, at).ok_or_else(bad)?;
+                let start = HEADER_SIZE + le_at(&block, at + 17, 4).ok_or_else(bad)? as usize;
+                let len = le_at(&block, at + 21, 4).ok_or_else(bad)? as usize;
+                let data = block.get(start..start + len).ok_or_else(bad)?;
+                f(TreeItem::new(objectid, r#type, offset, transid, data))
+            } else {
+                let at = HEADER_SIZE + i * KEY_PTR_SIZE;
+                let child = RootItem {
+                    bytenr: le_at(&block, at + 17, 8).ok_or_else(bad)?,
+                    refs: 1,
+                    level: level - 1,
+                };
+                self.walk_tree(child, f)?
+            };
+            if !go_on {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// full paths of the directories of a subvolume from the first link of each
+fn dir_paths(root: &Path, links: HashMap<u64, (u64, Vec<u8>)>) -> HashMap<u64, PathBuf> {
+    let mut ret = HashMap::from([(BTRFS_FIRST_FREE_OBJECTID, root.to_owned())]);
+    for &dir in links.keys() {
+        let mut chain = Vec::new();
+        let mut cur = dir;
+        while !ret.contains_key(&cur) {
+            match links.get(&cur) {
+                // a loop can only come from a damaged tree
+                Some((parent, _)) if chain.len() <= links.len() => {
+                    chain.push(cur);
+                    cur = *parent;
+                }
+                _ => {
+                    ret.insert(cur, root.join(format!("<inode {}>", cur)));
+                }
+            }
+        }
+        for ino in chain.into_iter().rev() {
+            let (parent, name) = &links[&ino];
+            let path = ret[parent].join(OsStr::from_bytes(name));
+            ret.insert(ino, path);
+        }
+    }
+    ret
+}
+
+// read every subvolume from the image, no kernel support needed
+pub(crate) fn walk(path: &Path, quit_sig: &AtomicBool, tx: WorkerTx) -> WalkInfo {
+    let mut info = WalkInfo::default();
+    if let Err(e) = walk_image(path, &mut info, quit_sig, &tx) {
+        quit_sig.store(true, Ordering::Release);
+        eprintln!("{}: {}", path.display(), e);
+    }
+    info
+}
+
+fn walk_image(
+    path: &Path,
+    info: &mut WalkInfo,
+    quit_sig: &AtomicBool,
+    tx: &WorkerTx,
+) -> io::Result<()> {
+    let (image, root) = Image::open(path)?;
+    info.generations.insert(image.fsid, image.generation);
+    let mut root_tree = RootTree::default();
+    image.walk_tree(root, &mut |item| {
+        root_tree.add(item);
+        true
+    })?;
+    // directories of every subvolume first, a subvolume can be moved into a newer one
+    let mut dirs = HashMap::new();
+    for (&id, &root) in &root_tree.subvols {
+        if quit_sig.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let mut dir_links = HashMap::new();
+        let mut dir = None;
+        image.walk_tree(root, &mut |item| {
+            match item.r#type() {
+                BTRFS_INODE_ITEM_KEY => {
+                    dir = item.inode().filter(|i| i.is_dir()).map(|_| item.objectid());
+                }
+                BTRFS_INODE_REF_KEY if dir == Some(item.objectid()) => {
+                    if let Some((parent, name)) = item.inode_ref() {
+                        dir_links.insert(item.objectid(), (parent, name.to_owned()));
+                    }
+                    dir = None;
+                }
+                _ => {}
+            }
+            !quit_sig.load(Ordering::Acquire)
+        })?;
+        // below the root of the subvolume
+        dirs.insert(id, dir_paths(Path::new(""), dir_links));
+    }
+    let dir_path = |parent, dir| Ok(dirs.get(&parent).and_then(|d| d.get(&dir)).cloned());
+    let roots = root_tree
+        .subvols
+        .keys()
+        .map(|&id| Ok((id, root_tree.path(id, dir_path)?)))
+        .collect::<io::Result<HashMap<_, _>>>()?;
+    for (id, root) in root_tree.subvols {
+        if quit_sig.load(Ordering::Acquire) {
+            break;
+        }
+        let subvol_root = roots[&id].clone();
+        let loc = Location {
+            fsid: image.fsid,
+            subvol: id,
+        };
+        info.subvol_paths.insert(loc, subvol_root.clone());
+        let mut subvol = Subvolume::new(None, subvol_root.clone(), loc, 0);
+        subvol.dirs = dirs
+            .remove(&id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(ino, dir)| match dir.as_os_str().is_empty() {
+                true => (ino, subvol_root.clone()),
+                false => (ino, subvol_root.join(dir)),
+            })
+            .collect();
+        subvol.send_files(quit_sig, tx, |f| image.walk_tree(root, f).map(drop))?;
+    }
+    Ok(())
+}
@@ -1,24 +1,206 @@
 use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
     env::args,
     fmt::{Display, Write},
-    fs::OpenOptions,
+    fs::File,
+    hash::{Hash, Hasher},
+    io,
     os::linux::fs::MetadataExt as _,
+    path::{Path, PathBuf},
     process::{self, exit},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread::scope,
 };
 
 use crossbeam::channel::{unbounded, Receiver, Sender};
-use dashmap::DashSet;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use nohash::BuildNoHashHasher;
 
+mod age;
+mod bookend;
 mod btrfs;
+mod cache;
+mod diff;
+mod du;
+mod fiemap;
+mod frag;
+mod hist;
+mod image;
+mod opts;
+mod record;
 mod scale;
-use btrfs::Sv2Args;
+mod send;
+mod source;
+mod sparse;
+mod state;
+#[cfg(test)]
+mod tests;
+mod tree;
+use btrfs::{Fsid, BTRFS_FIRST_FREE_OBJECTID};
+use opts::Opts;
 use scale::Scale;
+use source::ExtentSource;
 use walkdir::{DirEntry, WalkDir};
 
-type ExtentMap = DashSet<u64, BuildNoHashHasher<u64>>;
+// the details are only kept when a report needs them
+type ExtentMap = DashMap<ExtentId, Option<Box<ExtentRefs>>, BuildNoHashHasher<ExtentId>>;
+// extents already counted for a subvolume, ExtentMap only holds whole filesystems
+type SubvolExtents = DashSet<(u64, ExtentId)>;
+
+// what the workers share about the extents seen so far
+#[derive(Debug, Default)]
+struct Extents {
+    map: ExtentMap,
+    subvol: SubvolExtents,
+    fs: FsTable,
+}
+
+// the filesystems seen so far, extents refer to them by index
+#[derive(Debug, Default)]
+struct FsTable(Mutex<Vec<Fsid>>);
+impl FsTable {
+    fn index(&self, fsid: Fsid) -> u32 {
+        let mut fsids = self.0.lock().unwrap();
+        match fsids.iter().position(|&f| f == fsid) {
+            Some(i) => i as u32,
+            None => {
+                fsids.push(fsid);
+                fsids.len() as u32 - 1
+            }
+        }
+    }
+    fn fsids(&self) -> Vec<Fsid> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Location {
+    fsid: Fsid,
+    subvol: u64,
+}
+
+// disk_bytenr is only unique inside one filesystem, `fs` indexes the FsTable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ExtentId {
+    fs: u32,
+    bytenr: u64,
+}
+impl Hash for ExtentId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.bytenr ^ u64::from(self.fs).rotate_left(40));
+    }
+}
+impl nohash::IsEnabled for ExtentId {}
+
+// the set of arguments an extent is referenced from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum RefSet {
+    #[default]
+    Empty,
+    One(u32),
+    Many(Vec<u32>),
+}
+impl RefSet {
+    fn insert(&mut self, group: u32) {
+        match self {
+            RefSet::Empty => *self = RefSet::One(group),
+            RefSet::One(g) if *g == group => {}
+            RefSet::One(g) => *self = RefSet::Many(vec![*g, group]),
+            RefSet::Many(v) => {
+                if !v.contains(&group) {
+                    v.push(group);
+                }
+            }
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        let (one, many) = match self {
+            RefSet::Empty => (None, &[][..]),
+            RefSet::One(g) => (Some(*g), &[][..]),
+            RefSet::Many(v) => (None, &v[..]),
+        };
+        one.into_iter().chain(many.iter().copied())
+    }
+    fn single(&self) -> Option<u32> {
+        match self {
+            RefSet::One(g) => Some(*g),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ExtentRefs {
+    r#type: btrfs::ExtentType,
+    comp: btrfs::Compression,
+    disk: u64,
+    uncomp: u64,
+    // number of file extent items pointing here, and the bytes they reference
+    nref: u32,
+    refd: u64,
+    generation: u64,
+    // only kept for --du and diff
+    groups: RefSet,
+    // referenced ranges of the uncompressed extent, only kept for --bookend
+    ranges: Vec<(u64, u64)>,
+}
+
+// which details of the extents the enabled reports need
+#[derive(Debug, Clone, Copy, Default)]
+struct Track {
+    refs: bool,
+    groups: bool,
+    ranges: bool,
+}
+impl Track {
+    fn new(opts: &Opts) -> Self {
+        let groups = opts.du || opts.diff;
+        let ranges = opts.bookend;
+        let refs = groups
+            || ranges
+            || opts.ref_histogram
+            || opts.size_histogram
+            || opts.ratio_histogram
+            || opts.age;
+        Self {
+            refs,
+            groups,
+            ranges,
+        }
+    }
+}
+
+// number of rows in per extent kind tables: the compression types, then prealloc
+const NKIND: usize = btrfs::NCOMP + 1;
+const PREALLOC_KIND: usize = btrfs::NCOMP;
+impl ExtentRefs {
+    fn kind(&self) -> usize {
+        match self.r#type {
+            btrfs::ExtentType::Prealloc => PREALLOC_KIND,
+            _ => self.comp.as_usize(),
+        }
+    }
+}
+fn kind_name(kind: usize) -> &'static str {
+    if kind == PREALLOC_KIND {
+        "Prealloc"
+    } else {
+        btrfs::Compression::from_usize(kind).name()
+    }
+}
+
+// the regular and prealloc extents of one scanned file
+#[derive(Debug)]
+struct FileExtents {
+    path: PathBuf,
+    group: u32,
+    extents: Vec<ExtentId>,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 struct ExtentStat {
@@ -36,18 +218,20 @@ impl ExtentStat {
         self.disk == 0 && self.uncomp == 0 && self.refd == 0
     }
     fn get_percent(&self) -> u64 {
-        self.disk * 100 / self.uncomp
+        (self.disk * 100).checked_div(self.uncomp).unwrap_or(0)
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 struct CompsizeStat {
     nfile: u64,
     ninline: u64,
     nref: u64,
     nextent: u64,
+    nhole: u64,
+    hole: u64,
     prealloc: ExtentStat,
-    stat: [ExtentStat; 4],
+    stat: [ExtentStat; btrfs::NCOMP],
 }
 
 impl CompsizeStat {
@@ -56,6 +240,8 @@ impl CompsizeStat {
         self.ninline += rhs.ninline;
         self.nref += rhs.nref;
         self.nextent += rhs.nextent;
+        self.nhole += rhs.nhole;
+        self.hole += rhs.hole;
         self.prealloc.merge(rhs.prealloc);
         for (l, r) in self.stat.iter_mut().zip(rhs.stat) {
             l.merge(r);
@@ -73,11 +259,15 @@ struct CompsizeStatDisplay<'a> {
 impl<'a> Display for CompsizeStatDisplay<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let Self { stat, scale } = self;
-        writeln!(
+        write!(
             f,
-            "Processed {} files, {} regular extents ({} refs), {} inline.",
+            "Processed {} files, {} regular extents ({} refs), {} inline",
             stat.nfile, stat.nextent, stat.nref, stat.ninline
         )?;
+        if stat.nhole != 0 {
+            write!(f, ", {} holes", stat.nhole)?;
+        }
+        writeln!(f, ".")?;
         // Processed 3356969 files, 653492 regular extents (2242077 refs), 2018321 inline.
         // Type       Perc     Disk Usage   Uncompressed Referenced
         // TOTAL       78%     100146085502 127182733170 481020538738
@@ -105,8 +295,12 @@ impl<'a> Display for CompsizeStatDisplay<'a> {
             "Uncompressed",
             "Referenced",
         )?;
+        // sizes of encoded extents are guesses, and so is everything they're part of
+        let encoded = btrfs::Compression::Encoded.as_usize();
+        let estimated = |est: bool, s: String| if est { format!("~{}", s) } else { s };
         // total
         {
+            let est = !stat.stat[encoded].is_empty();
             let total_disk = stat.prealloc.disk + stat.stat.iter().map(|s| s.disk).sum::<u64>();
             let total_uncomp =
                 stat.prealloc.uncomp + stat.stat.iter().map(|s| s.uncomp).sum::<u64>();
@@ -115,9 +309,9 @@ impl<'a> Display for CompsizeStatDisplay<'a> {
             write_table(
                 f,
                 "TOTAL",
-                format_args!("{:3.0}%", total_percentage),
-                scale.scale(total_disk),
-                scale.scale(total_uncomp),
+                estimated(est, format!("{:3.0}%", total_percentage)),
+                estimated(est, scale.scale(total_disk)),
+                estimated(est, scale.scale(total_uncomp)),
                 scale.scale(total_refd),
             )?;
         }
@@ -126,12 +320,13 @@ impl<'a> Display for CompsizeStatDisplay<'a> {
             if s0.is_empty() {
                 continue;
             }
+            let est = i == encoded;
             write_table(
                 f,
                 btrfs::Compression::from_usize(i).name(),
-                format_args!("{:3.0}%", s0.get_percent()),
-                scale.scale(s0.disk),
-                scale.scale(s0.uncomp),
+                estimated(est, format!("{:3.0}%", s0.get_percent())),
+                estimated(est, scale.scale(s0.disk)),
+                estimated(est, scale.scale(s0.uncomp)),
                 scale.scale(s0.refd),
             )?;
         }
@@ -146,74 +341,312 @@ impl<'a> Display for CompsizeStatDisplay<'a> {
                 scale.scale(stat.prealloc.refd),
             )?;
         }
+        // holes
+        if stat.nhole != 0 {
+            write_table(f, "Holes", "", scale.scale(0), "", scale.scale(stat.hole))?;
+        }
         Ok(())
     }
 }
 
-type WorkerRx = Receiver<DirEntry>;
-type WorkerTx = Sender<DirEntry>;
+// keep the n items with the largest key, the list is trimmed lazily
+fn push_top<T, K: Ord>(top: &mut Vec<T>, item: T, n: usize, key: impl Fn(&T) -> K) {
+    top.push(item);
+    if top.len() >= n * 2 {
+        trim_top(top, n, key);
+    }
+}
+fn trim_top<T, K: Ord>(top: &mut Vec<T>, n: usize, key: impl Fn(&T) -> K) {
+    top.sort_unstable_by_key(|item| Reverse(key(item)));
+    top.truncate(n);
+}
+
+#[derive(Debug, Default)]
+struct ScanStats {
+    fs: BTreeMap<Fsid, CompsizeStat>,
+    subvol: BTreeMap<Location, CompsizeStat>,
+    // inline bytes per argument
+    inline: Vec<u64>,
+    files: Vec<FileExtents>,
+    // fragmentation per argument, and the worst files
+    frag: Vec<frag::FragStat>,
+    frag_top: Vec<(PathBuf, frag::FragStat)>,
+    sparse_top: Vec<sparse::SparseFile>,
+    // entries for the next run's cache
+    cache: cache::Cache,
+    recorded: Vec<record::FileRecord>,
+}
+impl ScanStats {
+    fn merge(&mut self, rhs: Self) {
+        for (group, r) in rhs.inline.into_iter().enumerate() {
+            *per_group(&mut self.inline, group as u32) += r;
+        }
+        for (fsid, stat) in rhs.fs {
+            self.fs.entry(fsid).or_default().merge(stat);
+        }
+        for (loc, stat) in rhs.subvol {
+            self.subvol.entry(loc).or_default().merge(stat);
+        }
+        self.files.extend(rhs.files);
+        for (group, r) in rhs.frag.into_iter().enumerate() {
+            per_group(&mut self.frag, group as u32).merge(r);
+        }
+        self.frag_top.extend(rhs.frag_top);
+        self.sparse_top.extend(rhs.sparse_top);
+        self.cache.merge(rhs.cache);
+        self.recorded.extend(rhs.recorded);
+    }
+}
+
+// per argument tables grow as the workers see new arguments
+fn per_group<T: Default>(v: &mut Vec<T>, group: u32) -> &mut T {
+    let group = group as usize;
+    if v.len() <= group {
+        v.resize_with(group + 1, T::default);
+    }
+    &mut v[group]
+}
+
+// only extents written after this transaction are counted, 0 counts everything
+#[derive(Debug, Clone, Default)]
+struct Since {
+    all: u64,
+    fs: BTreeMap<Fsid, u64>,
+}
+impl Since {
+    fn get(&self, fsid: Fsid) -> u64 {
+        self.fs.get(&fsid).copied().unwrap_or(self.all)
+    }
+}
+
+struct FileJob {
+    path: PathBuf,
+    ino: u64,
+    size: u64,
+    loc: Location,
+    // any directory in the same subvolume, searches go through it; None when replaying
+    fd: Option<Arc<File>>,
+    // index of the argument the file was found under
+    group: u32,
+    // already found by a tree search, the file isn't opened
+    items: Option<Vec<btrfs::IoctlSearchItem>>,
+    // only some ranges of the file are known, the gaps between them aren't holes
+    partial: bool,
+}
+
+type WorkerRx = Receiver<FileJob>;
+type WorkerTx = Sender<FileJob>;
 // blocking syscall: ioctl, should be run on multiple threads
 struct Worker<'map, 'sig> {
     rx: WorkerRx,
-    stat: CompsizeStat,
-    sv2_arg: Sv2Args,
-    extent_map: &'map ExtentMap,
+    stats: ScanStats,
+    source: Box<dyn ExtentSource + 'map>,
+    by_subvolume: bool,
+    // remember the extents of every file, for the top files of diff and bookend
+    track_files: bool,
+    track: Track,
+    fragmentation: bool,
+    sparse: bool,
+    top: usize,
+    since: Since,
+    extents: &'map Extents,
+    // the last filesystem looked up in the FsTable
+    fs: Option<(Fsid, u32)>,
     quit_sig: &'sig AtomicBool,
 }
 impl<'map, 'sig> Worker<'map, 'sig> {
-    fn new(recv: WorkerRx, extent_map: &'map ExtentMap, quit_sig: &'sig AtomicBool) -> Self {
+    fn new(
+        recv: WorkerRx,
+        opts: &Opts,
+        since: Since,
+        source: Box<dyn ExtentSource + 'map>,
+        extents: &'map Extents,
+        quit_sig: &'sig AtomicBool,
+    ) -> Self {
         Self {
             rx: recv,
-            stat: CompsizeStat::default(),
-            sv2_arg: Sv2Args::new(),
-            extent_map,
+            stats: ScanStats::default(),
+            source,
+            by_subvolume: opts.by_subvolume,
+            track_files: (opts.diff && opts.top > 0) || opts.bookend,
+            track: Track::new(opts),
+            fragmentation: opts.fragmentation,
+            sparse: opts.sparse,
+            top: opts.top_or_default(),
+            since,
+            extents,
+            fs: None,
             quit_sig,
         }
     }
 
-    fn run(mut self) -> CompsizeStat {
-        while let Ok(entry) = self.rx.recv() {
+    fn run(mut self) -> ScanStats {
+        let mut extents = Vec::new();
+        while let Ok(mut job) = self.rx.recv() {
             if self.quit_sig.load(Ordering::Acquire) {
                 break;
             }
-            let file = OpenOptions::new()
-                .read(true)
-                .write(false)
-                // .custom_flags(O_NOFOLLOW | O_NOCTTY | O_NONBLOCK)
-                .open(entry.path())
-                .unwrap();
-            let ino = entry.metadata().unwrap().st_ino();
-            match self.sv2_arg.search_file(file, ino) {
-                Ok(iter) => {
-                    self.stat.nfile += 1;
-                    for (key, comp, estat) in iter.filter_map(|item| item.parse().unwrap()) {
-                        merge_stat(self.extent_map, key, comp, estat, &mut self.stat);
-                    }
-                }
+            extents.clear();
+            match self.source.extents(&mut job, &mut extents) {
+                Ok(()) => self.account(job, &extents),
                 Err(e) => {
                     self.quit_sig.store(true, Ordering::Release);
-                    if e.raw_os_error() == 25 {
-                        eprintln!(
-                            "{}: Not btrfs (or SEARCH_V2 unsupported)",
-                            entry.path().display()
-                        );
-                    } else {
-                        eprintln!("{}: SEARCH_V2: {}", entry.path().display(), e);
-                    }
+                    eprintln!("{}: {}", job.path.display(), e);
                     break;
                 }
             }
         }
-        self.stat
+        self.source.finish(&mut self.stats);
+        self.stats
+    }
+
+    fn account(&mut self, job: FileJob, extents: &[btrfs::Extent]) {
+        let FileJob {
+            path,
+            size,
+            loc,
+            group,
+            partial,
+            ..
+        } = job;
+        let since = self.since.get(loc.fsid);
+        let fs = match self.fs {
+            Some((fsid, fs)) if fsid == loc.fsid => fs,
+            _ => {
+                let fs = self.extents.fs.index(loc.fsid);
+                self.fs = Some((loc.fsid, fs));
+                fs
+            }
+        };
+        let stat = self.stats.fs.entry(loc.fsid).or_default();
+        stat.nfile += 1;
+        // extents are deduplicated per subvolume too, so that every
+        // subvolume's numbers don't depend on the scan order
+        let mut subvol_stat = self.by_subvolume.then(|| {
+            let stat = self.stats.subvol.entry(loc).or_default();
+            stat.nfile += 1;
+            stat
+        });
+        let mut file_extents = Vec::new();
+        let mut frag = frag::FileFrag::default();
+        let mut holes = sparse::FileHoles::default();
+        let track = self.track;
+        for &ext in extents {
+            if ext.generation <= since && since != 0 {
+                continue;
+            }
+            frag.add(&ext);
+            holes.add(&ext);
+            let id = ExtentId {
+                fs,
+                bytenr: ext.key.key(),
+            };
+            if self.track_files && ext.key.r#type().has_extent() {
+                file_extents.push(id);
+            }
+            merge_stat(&ext, stat, || {
+                insert_extent(&self.extents.map, id, group, &ext, track)
+            });
+            if let Some(subvol_stat) = subvol_stat.as_deref_mut() {
+                let key = (loc.subvol, id);
+                merge_stat(&ext, subvol_stat, || self.extents.subvol.insert(key));
+            }
+            if ext.key.r#type() == btrfs::ExtentType::Inline {
+                *per_group(&mut self.stats.inline, group) += ext.stat.disk;
+            }
+        }
+        // gaps between the items of an incremental scan aren't holes
+        let (nhole, hole) = if since == 0 && !partial {
+            holes.finish(size)
+        } else {
+            (0, 0)
+        };
+        stat.nhole += nhole;
+        stat.hole += hole;
+        if let Some(subvol_stat) = subvol_stat {
+            subvol_stat.nhole += nhole;
+            subvol_stat.hole += hole;
+        }
+        if self.sparse && hole != 0 {
+            let file = sparse::SparseFile {
+                path: path.clone(),
+                size,
+                hole,
+            };
+            push_top(&mut self.stats.sparse_top, file, self.top, |f| f.hole);
+        }
+        if self.fragmentation {
+            let frag = frag.finish();
+            per_group(&mut self.stats.frag, group).merge(frag);
+            if frag.nrun > 1 {
+                let file = (path.clone(), frag);
+                push_top(&mut self.stats.frag_top, file, self.top, |f| f.1.nrun);
+            }
+        }
+        if self.track_files {
+            self.stats.files.push(FileExtents {
+                path,
+                group,
+                extents: file_extents,
+            });
+        }
     }
 }
-fn merge_stat(
+// returns true if the extent wasn't seen before
+fn insert_extent(
     extent_map: &ExtentMap,
-    key: btrfs::ExtentKey,
-    comp: btrfs::Compression,
-    stat: ExtentStat,
-    ret: &mut CompsizeStat,
-) {
+    id: ExtentId,
+    group: u32,
+    ext: &btrfs::Extent,
+    track: Track,
+) -> bool {
+    let range = (ext.offset, ext.offset + ext.stat.refd);
+    match extent_map.entry(id) {
+        Entry::Occupied(mut e) => {
+            if let Some(refs) = e.get_mut() {
+                refs.nref += 1;
+                refs.refd += ext.stat.refd;
+                if track.groups {
+                    refs.groups.insert(group);
+                }
+                if track.ranges {
+                    bookend::add_range(&mut refs.ranges, range);
+                }
+            }
+            false
+        }
+        Entry::Vacant(e) => {
+            let refs = track.refs.then(|| {
+                Box::new(ExtentRefs {
+                    r#type: ext.key.r#type(),
+                    comp: ext.comp,
+                    disk: ext.stat.disk,
+                    uncomp: ext.stat.uncomp,
+                    nref: 1,
+                    refd: ext.stat.refd,
+                    generation: ext.generation,
+                    groups: if track.groups {
+                        RefSet::One(group)
+                    } else {
+                        RefSet::Empty
+                    },
+                    ranges: if track.ranges {
+                        vec![range]
+                    } else {
+                        Vec::new()
+                    },
+                })
+            });
+            e.insert(refs);
+            true
+        }
+    }
+}
+// `is_new` records the extent and returns true if it wasn't seen before
+fn merge_stat(ext: &btrfs::Extent, ret: &mut CompsizeStat, is_new: impl FnOnce() -> bool) {
+    let btrfs::Extent {
+        key, comp, stat, ..
+    } = *ext;
     match key.r#type() {
         btrfs::ExtentType::Inline => {
             ret.ninline += 1;
@@ -223,7 +656,7 @@ fn merge_stat(
         }
         btrfs::ExtentType::Regular => {
             ret.nref += 1;
-            if extent_map.insert(key.key()) {
+            if is_new() {
                 ret.nextent += 1;
                 ret.stat[comp.as_usize()].disk += stat.disk;
                 ret.stat[comp.as_usize()].uncomp += stat.uncomp;
@@ -232,75 +665,401 @@ fn merge_stat(
         }
         btrfs::ExtentType::Prealloc => {
             ret.nref += 1;
-            if extent_map.insert(key.key()) {
+            if is_new() {
                 ret.nextent += 1;
                 ret.prealloc.disk += stat.disk;
                 ret.prealloc.uncomp += stat.uncomp;
             }
             ret.prealloc.refd += stat.refd;
         }
+        // counted per file, together with implicit holes
+        btrfs::ExtentType::Hole => {}
     }
 }
-fn do_file(entry: DirEntry, workers: &WorkerTx) {
-    workers.send(entry).unwrap();
+fn do_file(entry: DirEntry, loc: Location, fd: Arc<File>, group: u32, workers: &WorkerTx) {
+    let meta = entry.metadata().unwrap();
+    let job = FileJob {
+        ino: meta.st_ino(),
+        size: meta.st_size(),
+        path: entry.into_path(),
+        loc,
+        fd: Some(fd),
+        group,
+        items: None,
+        partial: false,
+    };
+    workers.send(job).unwrap();
 }
 
-fn main() {
-    let (ftx, frx) = unbounded();
-    let extent_map = DashSet::with_hasher(BuildNoHashHasher::default());
-    let quit_sig = AtomicBool::new(false);
-    let final_stat = scope(|ex| {
-        let args: Vec<_> = args().skip(1).collect();
+type SubvolFd = (Location, Arc<File>);
+
+// st_dev differs between subvolumes, so a device number maps to exactly one subvolume
+// returns the filesystem info too when the device is new
+// any_fs: other filesystems are taken as a btrfs with a single subvolume
+fn lookup_location(
+    entry: &DirEntry,
+    locations: &mut HashMap<u64, SubvolFd>,
+    any_fs: bool,
+) -> io::Result<(SubvolFd, Option<btrfs::FsInfo>)> {
+    let dev = entry.metadata().unwrap().st_dev();
+    if let Some(found) = locations.get(&dev) {
+        return Ok((found.clone(), None));
+    }
+    // the directory holding the first file stays open for every search in the subvolume
+    let dir = match entry.path().parent() {
+        // a bare file name as argument
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => entry.path(),
+    };
+    let file = File::open(dir)?;
+    let (info, subvol) = match btrfs::fs_info(&file) {
+        Ok(info) => (info, btrfs::subvol_id(&file)?),
+        // the device number stands in for the fsid
+        Err(e) if any_fs && e.raw_os_error() == 25 => {
+            let mut fsid = Fsid::default();
+            fsid.0[..8].copy_from_slice(&dev.to_le_bytes());
+            let info = btrfs::FsInfo {
+                fsid,
+                generation: 0,
+            };
+            (info, btrfs::BTRFS_FS_TREE_OBJECTID)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let loc = Location {
+        fsid: info.fsid,
+        subvol,
+    };
+    let found = (loc, Arc::new(file));
+    locations.insert(dev, found.clone());
+    Ok((found, Some(info)))
+}
+
+#[derive(Debug, Default)]
+struct WalkInfo {
+    subvol_paths: BTreeMap<Location, PathBuf>,
+    // generation of every filesystem at the time it was first seen
+    generations: BTreeMap<Fsid, u64>,
+}
+
+// walk all paths and feed regular files to the workers
+fn walk(paths: Vec<String>, any_fs: bool, quit_sig: &AtomicBool, tx: WorkerTx) -> WalkInfo {
+    let mut locations = HashMap::new();
+    let mut roots = HashMap::new();
+    let mut info = WalkInfo::default();
+    for (group, arg) in paths.into_iter().enumerate() {
+        for entry in WalkDir::new(&arg)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(|e| e.ok())
         {
-            let quit_sig = &quit_sig;
-            ex.spawn(move || {
-                for arg in args {
-                    for entry in WalkDir::new(arg)
-                        .follow_links(false)
-                        .into_iter()
-                        .filter_map(|e| {
-                            let e = e.ok()?;
-                            if e.metadata().unwrap().is_file() {
-                                Some(e)
-                            } else {
-                                None
-                            }
-                        })
-                    {
-                        if quit_sig.load(Ordering::Acquire) {
-                            return;
-                        }
-                        do_file(entry, &ftx);
+            if quit_sig.load(Ordering::Acquire) {
+                return info;
+            }
+            let meta = entry.metadata().unwrap();
+            if meta.is_dir() && meta.st_ino() == BTRFS_FIRST_FREE_OBJECTID {
+                roots.insert(meta.st_dev(), entry.path().to_owned());
+            }
+            if !meta.is_file() {
+                continue;
+            }
+            let (loc, fd) = match lookup_location(&entry, &mut locations, any_fs) {
+                Ok((found, None)) => found,
+                Ok(((loc, fd), Some(fs_info))) => {
+                    // the argument itself may live below the subvolume root
+                    let path = roots.get(&meta.st_dev()).cloned();
+                    info.subvol_paths
+                        .entry(loc)
+                        .or_insert_with(|| path.unwrap_or_else(|| arg.clone().into()));
+                    info.generations.insert(fs_info.fsid, fs_info.generation);
+                    (loc, fd)
+                }
+                Err(e) => {
+                    quit_sig.store(true, Ordering::Release);
+                    if e.raw_os_error() == Some(25) {
+                        eprintln!("{}: Not btrfs", entry.path().display());
+                    } else {
+                        eprintln!("{}: FS_INFO: {}", entry.path().display(), e);
                     }
+                    return info;
                 }
-            });
+            };
+            do_file(entry, loc, fd, group as u32, &tx);
+        }
+    }
+    info
+}
+
+fn main() {
+    let mut opts = Opts::parse(args());
+    let replay = opts.replay.as_ref().map(|path| {
+        record::load(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        })
+    });
+    if let Some(rec) = &replay {
+        if opts.paths.is_empty() {
+            opts.paths = rec.paths.clone();
+        } else if opts.paths.len() != rec.paths.len() {
+            // files are tied to the recorded arguments by index, paths only relabel them
+            eprintln!(
+                "replay needs as many paths as recorded ({})",
+                rec.paths.len()
+            );
+            exit(1);
         }
+        if opts.diff && opts.paths.len() != 2 {
+            eprintln!("diff needs a recording of two paths");
+            exit(1);
+        }
+    }
+    let (ftx, frx) = unbounded();
+    let extents = Extents::default();
+    let quit_sig = AtomicBool::new(false);
+    let old_state = match &opts.state {
+        Some(path) => state::load(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }),
+        None => BTreeMap::new(),
+    };
+    let since = match opts.since_transid {
+        Some(transid) => Since {
+            all: transid,
+            fs: BTreeMap::new(),
+        },
+        None => Since {
+            all: 0,
+            fs: old_state.clone(),
+        },
+    };
+    let old_cache = opts.cache.as_ref().map(|path| {
+        cache::load(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        })
+    });
+    let (stats, walk_info) = scope(|ex| {
+        let walker = {
+            let quit_sig = &quit_sig;
+            let paths = opts.paths.clone();
+            if let Some(rec) = replay {
+                ex.spawn(move || record::replay(rec, quit_sig, ftx))
+            } else if let Some(image) = &opts.image {
+                ex.spawn(move || image::walk(image, quit_sig, ftx))
+            } else if let Some(stream) = &opts.send {
+                ex.spawn(move || send::walk(stream, quit_sig, ftx))
+            } else if opts.subvol_id.is_some() || opts.all_subvolumes {
+                let id = opts.subvol_id;
+                ex.spawn(move || tree::walk_ids(paths, id, quit_sig, ftx))
+            } else if opts.whole_subvolume {
+                // --du and diff count the files under every path they're in
+                let per_group = opts.du || opts.diff;
+                ex.spawn(move || tree::walk(paths, per_group, quit_sig, ftx))
+            } else {
+                let any_fs = opts.fiemap;
+                ex.spawn(move || walk(paths, any_fs, quit_sig, ftx))
+            }
+        };
         let handles: Vec<_> = (0..4)
             .map(|_| {
-                let worker = Worker::new(frx.clone(), &extent_map, &quit_sig);
+                let source: Box<dyn ExtentSource> = if opts.fiemap {
+                    Box::new(source::Fiemap)
+                } else {
+                    Box::new(source::TreeSearch::new(
+                        since.clone(),
+                        old_cache.as_ref(),
+                        opts.record.is_some(),
+                    ))
+                };
+                let worker = Worker::new(
+                    frx.clone(),
+                    &opts,
+                    since.clone(),
+                    source,
+                    &extents,
+                    &quit_sig,
+                );
                 ex.spawn(|| worker.run())
             })
             .collect();
-        handles
+        let stats = handles
             .into_iter()
             .map(|h| h.join().unwrap())
             .reduce(|mut a, b| {
                 a.merge(b);
                 a
             })
-            .unwrap()
+            .unwrap();
+        (stats, walker.join().unwrap())
     });
     if quit_sig.load(Ordering::Acquire) {
         process::exit(1);
     }
 
-    println!("{}", extent_map.len());
+    if let Some(path) = &opts.cache {
+        // files that are gone drop out of the cache
+        if let Err(e) = cache::save(path, &stats.cache) {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Some(path) = &opts.record {
+        let mut files = stats.recorded;
+        files.sort_unstable_by(|a, b| (a.group, &a.path).cmp(&(b.group, &b.path)));
+        let rec = record::Recording {
+            paths: opts.paths.clone(),
+            subvol_paths: walk_info.subvol_paths.clone(),
+            generations: walk_info.generations.clone(),
+            files,
+        };
+        if let Err(e) = record::save(path, &rec) {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Some(path) = &opts.state {
+        // the running transaction may get more extents after we looked, count it again next time
+        let scanned = walk_info
+            .generations
+            .iter()
+            .map(|(fsid, gen)| (*fsid, gen.saturating_sub(1)))
+            .collect();
+        if let Err(e) = state::save(path, old_state, &scanned) {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+
+    println!("{}", extents.map.len());
+    let mut final_stat = CompsizeStat::default();
+    for stat in stats.fs.values() {
+        final_stat.merge(stat.clone());
+    }
+    let incremental = opts.since_transid.is_some() || opts.state.is_some();
     if final_stat.nfile == 0 {
         eprintln!("No files.");
         exit(1);
-    } else if final_stat.nref == 0 {
+    } else if final_stat.nref == 0 && !incremental {
         eprintln!("All empty or still-delalloced files.");
         exit(1);
     }
+    if incremental {
+        for fsid in stats.fs.keys() {
+            println!(
+                "Filesystem {}: extents newer than transid {}",
+                fsid,
+                since.get(*fsid)
+            );
+        }
+    }
+    if opts.diff {
+        let diff_stat = diff::DiffStat::collect(&extents.map);
+        println!("A: {}\nB: {}", opts.paths[0], opts.paths[1]);
+        println!("{}", diff_stat.display(Scale::default()));
+        if opts.top > 0 {
+            let top = diff::top_files(&extents.map, stats.files, opts.top);
+            println!(
+                "{}",
+                diff::TopFilesDisplay {
+                    top: &top,
+                    scale: Scale::default()
+                }
+            );
+        }
+        return;
+    }
+    let multi_fs = stats.fs.len() > 1;
+    if opts.by_subvolume {
+        for (loc, stat) in &stats.subvol {
+            let path = walk_info.subvol_paths[loc].display();
+            if multi_fs {
+                println!("Subvolume {} on {} ({}):", loc.subvol, loc.fsid, path);
+            } else {
+                println!("Subvolume {} ({}):", loc.subvol, path);
+            }
+            println!("{}", stat.display(Scale::default()));
+        }
+    }
+    if multi_fs {
+        for (fsid, stat) in &stats.fs {
+            println!("Filesystem {}:", fsid);
+            println!("{}", stat.display(Scale::default()));
+        }
+    }
+    if opts.by_subvolume || multi_fs {
+        println!("Total:");
+    }
     println!("{}", final_stat.display(Scale::default()));
+    if opts.fiemap {
+        println!(
+            "FIEMAP: ~ marks estimates, the algorithm and compressed size of encoded extents \
+             aren't reported; partly shared or physically adjacent extents may be miscounted"
+        );
+    }
+    if opts.ref_histogram {
+        let hist = hist::RefHistogram::collect(&extents.map);
+        println!("{}", hist.display(Scale::default()));
+    }
+    if opts.size_histogram {
+        let hist = hist::SizeHistogram::collect(&extents.map);
+        println!("{}", hist.display(Scale::default()));
+    }
+    if opts.ratio_histogram {
+        let hist = hist::RatioHistogram::collect(&extents.map);
+        println!("{}", hist.display(Scale::default()));
+    }
+    if opts.age {
+        let age = age::AgeStat::collect(&extents.map, &extents.fs.fsids(), &walk_info.generations);
+        println!("{}", age.display(Scale::default()));
+    }
+    if opts.bookend {
+        let waste = bookend::WasteStat::collect(&extents.map);
+        println!("{}", waste.display(Scale::default()));
+        let top = bookend::top_files(&extents.map, stats.files, opts.top_or_default());
+        println!(
+            "{}",
+            bookend::TopFilesDisplay {
+                top: &top,
+                scale: Scale::default()
+            }
+        );
+    }
+    if opts.fragmentation {
+        let mut frag_stats = stats.frag;
+        frag_stats.resize(opts.paths.len(), Default::default());
+        let mut top = stats.frag_top;
+        trim_top(&mut top, opts.top_or_default(), |f| f.1.nrun);
+        let frag = frag::FragDisplay {
+            paths: &opts.paths,
+            stats: &frag_stats,
+            top: &top,
+            scale: Scale::default(),
+        };
+        println!("{}", frag);
+    }
+    if opts.sparse {
+        let mut top = stats.sparse_top;
+        trim_top(&mut top, opts.top_or_default(), |f| f.hole);
+        println!(
+            "{}",
+            sparse::SparseDisplay {
+                top: &top,
+                scale: Scale::default()
+            }
+        );
+    }
+    if opts.du {
+        let mut inline = stats.inline;
+        inline.resize(opts.paths.len(), 0);
+        let du_stats = du::collect(&extents.map, &inline);
+        let du = du::DuDisplay {
+            paths: &opts.paths,
+            stats: &du_stats,
+            scale: Scale::default(),
+        };
+        println!("{}", du);
+    }
 }
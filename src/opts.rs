@@ -0,0 +1,229 @@
+use std::{env::Args, path::PathBuf, process::exit, str::FromStr};
+
+const USAGE: &str = "Usage: compsize-rs [OPTIONS] <path>...
+       compsize-rs diff [--top N] <path-A> <path-B>
+
+Options:
+    --by-subvolume    show a breakdown per subvolume
+    --du              show exclusive and shared disk usage per path
+    --bookend         show disk pinned by unreferenced parts of extents
+    --fragmentation   show extent counts and physically contiguous runs per path and file
+    --sparse          list the files with most bytes in holes
+    --age             show disk usage and compression by extent age in transactions
+    --since-transid N only count extents written after transaction N
+    --state FILE      only count extents written since the run that last updated FILE
+    --cache FILE      reuse the extents of inodes unchanged since the run that wrote FILE
+    --record FILE     save the raw search results to FILE
+    --replay FILE     rerun on the search results saved in FILE, the paths default to the
+                      recorded ones and others only rename them
+    --image FILE      read every subvolume from an unmounted image or block device, no
+                      paths are given
+    --send FILE       show what receiving the send stream in FILE (- for stdin) would
+                      create, incremental streams only show what they carry
+    --fiemap          use FIEMAP, which needs no privileges but only gives estimates;
+                      extents shared with files outside the paths count as unshared,
+                      as they do without it
+    --whole-subvolume search the whole subvolume each path is in at once instead of walking
+                      it, nested subvolumes are left out and hard links counted once
+    --subvol-id N     search subvolume N on the filesystem of every path, mounted or not
+    --all-subvolumes  search every subvolume on the filesystem of every path
+    --ref-histogram   show how many extents are referenced how many times
+    --size-histogram  show extent sizes per compression type
+    --ratio-histogram show how well extents compress, weighted by size
+    --top N           diff, bookend, fragmentation, sparse: number of files listed";
+
+#[derive(Debug, Clone, Default)]
+pub struct Opts {
+    pub by_subvolume: bool,
+    pub du: bool,
+    pub bookend: bool,
+    pub fragmentation: bool,
+    pub sparse: bool,
+    pub age: bool,
+    pub since_transid: Option<u64>,
+    pub state: Option<PathBuf>,
+    pub cache: Option<PathBuf>,
+    pub whole_subvolume: bool,
+    pub subvol_id: Option<u64>,
+    pub all_subvolumes: bool,
+    pub fiemap: bool,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub image: Option<PathBuf>,
+    pub send: Option<PathBuf>,
+    pub ref_histogram: bool,
+    pub size_histogram: bool,
+    pub ratio_histogram: bool,
+    pub diff: bool,
+    pub top: usize,
+    pub paths: Vec<String>,
+}
+
+impl Opts {
+    pub fn parse(args: Args) -> Self {
+        let mut opts = Self::default();
+        let mut args = args.skip(1).peekable();
+        if args.peek().map(String::as_str) == Some("diff") {
+            args.next();
+            opts.diff = true;
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--by-subvolume" => opts.by_subvolume = true,
+                "--du" => opts.du = true,
+                "--bookend" => opts.bookend = true,
+                "--fragmentation" => opts.fragmentation = true,
+                "--sparse" => opts.sparse = true,
+                "--age" => opts.age = true,
+                "--since-transid" => opts.since_transid = Some(parse_value(&arg, args.next())),
+                "--state" => opts.state = Some(parse_value(&arg, args.next())),
+                "--cache" => opts.cache = Some(parse_value(&arg, args.next())),
+                "--whole-subvolume" => opts.whole_subvolume = true,
+                "--subvol-id" => opts.subvol_id = Some(parse_value(&arg, args.next())),
+                "--all-subvolumes" => opts.all_subvolumes = true,
+                "--fiemap" => opts.fiemap = true,
+                "--record" => opts.record = Some(parse_value(&arg, args.next())),
+                "--replay" => opts.replay = Some(parse_value(&arg, args.next())),
+                "--image" => opts.image = Some(parse_value(&arg, args.next())),
+                "--send" => opts.send = Some(parse_value(&arg, args.next())),
+                "--ref-histogram" => opts.ref_histogram = true,
+                "--size-histogram" => opts.size_histogram = true,
+                "--ratio-histogram" => opts.ratio_histogram = true,
+                "--top" => opts.top = parse_value(&arg, args.next()),
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    exit(0);
+                }
+                "--" => {
+                    opts.paths.extend(args.by_ref());
+                }
+                _ if arg.starts_with('-') => {
+                    eprintln!("Unknown option: {}\n{}", arg, USAGE);
+                    exit(1);
+                }
+                _ => opts.paths.push(arg),
+            }
+        }
+        // a replay takes the recorded paths unless given others
+        let read_whole = opts.image.is_some() || opts.send.is_some();
+        let wrong_paths = match opts.paths.len() {
+            0 => opts.replay.is_none() && !read_whole,
+            n => read_whole || opts.diff && n != 2,
+        };
+        if wrong_paths {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+        // an incremental scan only sees part of every file
+        conflicts(
+            ("--cache", opts.cache.is_some()),
+            &[
+                ("--since-transid", opts.since_transid.is_some()),
+                ("--state", opts.state.is_some()),
+                // the tree search finds the items itself, nothing would be cached
+                ("--whole-subvolume", opts.whole_subvolume),
+                ("--subvol-id", opts.subvol_id.is_some()),
+                ("--all-subvolumes", opts.all_subvolumes),
+            ],
+        );
+        // FIEMAP tells neither generations nor where a reference starts inside its extent
+        conflicts(
+            ("--fiemap", opts.fiemap),
+            &[
+                ("--since-transid", opts.since_transid.is_some()),
+                ("--state", opts.state.is_some()),
+                ("--cache", opts.cache.is_some()),
+                ("--whole-subvolume", opts.whole_subvolume),
+                ("--subvol-id", opts.subvol_id.is_some()),
+                ("--all-subvolumes", opts.all_subvolumes),
+                ("--age", opts.age),
+                ("--bookend", opts.bookend),
+            ],
+        );
+        // only what SEARCH_V2 returned for every single file gets recorded
+        let not_recorded = [
+            ("--since-transid", opts.since_transid.is_some()),
+            ("--state", opts.state.is_some()),
+            ("--cache", opts.cache.is_some()),
+            ("--whole-subvolume", opts.whole_subvolume),
+            ("--subvol-id", opts.subvol_id.is_some()),
+            ("--all-subvolumes", opts.all_subvolumes),
+            ("--fiemap", opts.fiemap),
+        ];
+        conflicts(("--record", opts.record.is_some()), &not_recorded);
+        conflicts(("--replay", opts.replay.is_some()), &not_recorded);
+        conflicts(
+            ("--replay", opts.replay.is_some()),
+            &[("--record", opts.record.is_some())],
+        );
+        // images and streams are read whole and on their own
+        let not_read_whole = [
+            ("diff", opts.diff),
+            ("--since-transid", opts.since_transid.is_some()),
+            ("--state", opts.state.is_some()),
+            ("--cache", opts.cache.is_some()),
+            ("--whole-subvolume", opts.whole_subvolume),
+            ("--subvol-id", opts.subvol_id.is_some()),
+            ("--all-subvolumes", opts.all_subvolumes),
+            ("--fiemap", opts.fiemap),
+            ("--record", opts.record.is_some()),
+            ("--replay", opts.replay.is_some()),
+        ];
+        conflicts(("--image", opts.image.is_some()), &not_read_whole);
+        conflicts(("--send", opts.send.is_some()), &not_read_whole);
+        // nothing is written in a transaction before it's received
+        conflicts(
+            ("--send", opts.send.is_some()),
+            &[("--image", opts.image.is_some()), ("--age", opts.age)],
+        );
+        conflicts(
+            ("--subvol-id", opts.subvol_id.is_some()),
+            &[
+                ("--all-subvolumes", opts.all_subvolumes),
+                ("--whole-subvolume", opts.whole_subvolume),
+            ],
+        );
+        conflicts(
+            ("--all-subvolumes", opts.all_subvolumes),
+            &[("--whole-subvolume", opts.whole_subvolume)],
+        );
+        // the breakdown is what these are for, the total follows it
+        if opts.subvol_id.is_some() || opts.all_subvolumes {
+            opts.by_subvolume = true;
+        }
+        if let Some(input) = opts.image.as_ref().or(opts.send.as_ref()) {
+            opts.paths = vec![input.display().to_string()];
+        }
+        opts
+    }
+
+    // diff lists no files unless asked to, the other reports default to 10
+    pub fn top_or_default(&self) -> usize {
+        if self.top == 0 {
+            10
+        } else {
+            self.top
+        }
+    }
+}
+
+// exits if `opt` is given together with any of `others`
+fn conflicts(opt: (&str, bool), others: &[(&str, bool)]) {
+    if !opt.1 {
+        return;
+    }
+    if let Some((other, _)) = others.iter().find(|(_, set)| *set) {
+        eprintln!("{} can't be combined with {}", opt.0, other);
+        exit(1);
+    }
+}
+
+fn parse_value<T: FromStr>(opt: &str, value: Option<String>) -> T {
+    match value.as_deref().map(str::parse) {
+        Some(Ok(v)) => v,
+        _ => {
+            eprintln!("Invalid or missing value for {}\n{}", opt, USAGE);
+            exit(1);
+        }
+    }
+}
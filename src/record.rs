@@ -0,0 +1,181 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    io::{self, BufWriter, ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    btrfs::{Fsid, RawBatch},
+    FileJob, Location, WalkInfo, WorkerTx,
+};
+
+const MAGIC: &[u8; 8] = b"CSRSRECD";
+const VERSION: u32 = 1;
+
+// one scanned file and the search results for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub ino: u64,
+    pub size: u64,
+    pub loc: Location,
+    pub group: u32,
+    pub batches: Vec<RawBatch>,
+}
+
+// everything a run saw, apart from the options
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub paths: Vec<String>,
+    pub subvol_paths: BTreeMap<Location, PathBuf>,
+    pub generations: BTreeMap<Fsid, u64>,
+    pub files: Vec<FileRecord>,
+}
+
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.rest.len() < n {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Truncated recording",
+            ));
+        }
+        let (ret, rest) = self.rest.split_at(n);
+        self.rest = rest;
+        Ok(ret)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    fn fsid(&mut self) -> io::Result<Fsid> {
+        Ok(Fsid(self.bytes(16)?.try_into().unwrap()))
+    }
+    fn path(&mut self) -> io::Result<PathBuf> {
+        let len = self.u32()? as usize;
+        Ok(OsStr::from_bytes(self.bytes(len)?).into())
+    }
+}
+
+fn write_path(w: &mut impl Write, path: &Path) -> io::Result<()> {
+    let path = path.as_os_str().as_bytes();
+    w.write_all(&(path.len() as u32).to_le_bytes())?;
+    w.write_all(path)
+}
+
+pub fn load(path: &Path) -> io::Result<Recording> {
+    let buf = fs::read(path)?;
+    let mut r = Reader { rest: &buf };
+    if r.bytes(8).ok() != Some(MAGIC) || r.u32()? != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Invalid recording"));
+    }
+    let mut ret = Recording::default();
+    for _ in 0..r.u32()? {
+        ret.paths.push(r.path()?.to_string_lossy().into_owned());
+    }
+    for _ in 0..r.u32()? {
+        let loc = Location {
+            fsid: r.fsid()?,
+            subvol: r.u64()?,
+        };
+        ret.subvol_paths.insert(loc, r.path()?);
+    }
+    for _ in 0..r.u32()? {
+        ret.generations.insert(r.fsid()?, r.u64()?);
+    }
+    while !r.rest.is_empty() {
+        let mut file = FileRecord {
+            path: r.path()?,
+            ino: r.u64()?,
+            size: r.u64()?,
+            loc: Location {
+                fsid: r.fsid()?,
+                subvol: r.u64()?,
+            },
+            group: r.u32()?,
+            batches: Vec::new(),
+        };
+        // reports index the paths by group and the subvolume paths by location
+        if file.group as usize >= ret.paths.len() || !ret.subvol_paths.contains_key(&file.loc) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid recording"));
+        }
+        for _ in 0..r.u32()? {
+            let nr_items = r.u32()?;
+            let len = r.u32()? as usize;
+            let buf = r.bytes(len)?.to_vec();
+            file.batches.push(RawBatch { nr_items, buf });
+        }
+        ret.files.push(file);
+    }
+    Ok(ret)
+}
+
+pub fn save(path: &Path, rec: &Recording) -> io::Result<()> {
+    let mut w = BufWriter::new(fs::File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(rec.paths.len() as u32).to_le_bytes())?;
+    for path in &rec.paths {
+        write_path(&mut w, path.as_ref())?;
+    }
+    w.write_all(&(rec.subvol_paths.len() as u32).to_le_bytes())?;
+    for (loc, path) in &rec.subvol_paths {
+        w.write_all(&loc.fsid.0)?;
+        w.write_all(&loc.subvol.to_le_bytes())?;
+        write_path(&mut w, path)?;
+    }
+    w.write_all(&(rec.generations.len() as u32).to_le_bytes())?;
+    for (fsid, gen) in &rec.generations {
+        w.write_all(&fsid.0)?;
+        w.write_all(&gen.to_le_bytes())?;
+    }
+    for file in &rec.files {
+        write_path(&mut w, &file.path)?;
+        w.write_all(&file.ino.to_le_bytes())?;
+        w.write_all(&file.size.to_le_bytes())?;
+        w.write_all(&file.loc.fsid.0)?;
+        w.write_all(&file.loc.subvol.to_le_bytes())?;
+        w.write_all(&file.group.to_le_bytes())?;
+        w.write_all(&(file.batches.len() as u32).to_le_bytes())?;
+        for batch in &file.batches {
+            w.write_all(&batch.nr_items.to_le_bytes())?;
+            w.write_all(&(batch.buf.len() as u32).to_le_bytes())?;
+            w.write_all(&batch.buf)?;
+        }
+    }
+    w.into_inner()?.sync_all()
+}
+
+// feed the recorded files to the workers instead of walking
+pub(crate) fn replay(rec: Recording, quit_sig: &AtomicBool, tx: WorkerTx) -> WalkInfo {
+    for file in rec.files {
+        if quit_sig.load(Ordering::Acquire) {
+            break;
+        }
+        let items = file.batches.iter().flat_map(RawBatch::items).collect();
+        let job = FileJob {
+            path: file.path,
+            ino: file.ino,
+            size: file.size,
+            loc: file.loc,
+            fd: None,
+            group: file.group,
+            items: Some(items),
+            partial: false,
+        };
+        tx.send(job).unwrap();
+    }
+    WalkInfo {
+        subvol_paths: rec.subvol_paths,
+        generations: rec.generations,
+    }
+}
@@ -1,5 +1,6 @@
 const UNITS: &[u8; 6] = b"KMGTPE";
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     Binary,
@@ -52,6 +53,9 @@ impl Scale {
                 cnt += 1;
             }
             if num < base {
+                if cnt == 0 {
+                    return format!("{}B", num);
+                }
                 return format!("{} {}{}", num, UNITS[cnt - 1] as char, suffix);
             } else {
                 let num = num as f64 / base as f64;
@@ -0,0 +1,633 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    btrfs::{
+        Compression, FileExtentItem, Fsid, IoctlSearchItem, BTRFS_FILE_EXTENT_INLINE,
+        BTRFS_FILE_EXTENT_PREALLOC, BTRFS_FILE_EXTENT_REG, BTRFS_FIRST_FREE_OBJECTID,
+    },
+    FileJob, Location, WalkInfo, WorkerTx,
+};
+
+const MAGIC: &[u8; 13] = b"btrfs-stream\0";
+const CMD_HEADER_SIZE: usize = 10;
+// the largest command the kernel sends, header included, from v2 on it holds a
+// whole compressed extent
+const MAX_CMD_SIZE_V1: usize = 64 << 10;
+const MAX_CMD_SIZE_V2: usize = (16 << 10) + (128 << 10);
+
+const CMD_SUBVOL: u16 = 1;
+const CMD_SNAPSHOT: u16 = 2;
+const CMD_MKFILE: u16 = 3;
+const CMD_RENAME: u16 = 9;
+const CMD_LINK: u16 = 10;
+const CMD_UNLINK: u16 = 11;
+const CMD_WRITE: u16 = 15;
+const CMD_CLONE: u16 = 16;
+const CMD_TRUNCATE: u16 = 17;
+const CMD_END: u16 = 21;
+const CMD_UPDATE_EXTENT: u16 = 22;
+const CMD_FALLOCATE: u16 = 23;
+const CMD_ENCODED_WRITE: u16 = 25;
+
+const ATTR_UUID: u16 = 1;
+const ATTR_SIZE: u16 = 4;
+const ATTR_PATH: u16 = 15;
+const ATTR_PATH_TO: u16 = 16;
+const ATTR_PATH_LINK: u16 = 17;
+const ATTR_FILE_OFFSET: u16 = 18;
+const ATTR_DATA: u16 = 19;
+const ATTR_CLONE_UUID: u16 = 20;
+const ATTR_CLONE_PATH: u16 = 22;
+const ATTR_CLONE_OFFSET: u16 = 23;
+const ATTR_CLONE_LEN: u16 = 24;
+const ATTR_FALLOCATE_MODE: u16 = 25;
+const ATTR_UNENCODED_FILE_LEN: u16 = 27;
+const ATTR_UNENCODED_LEN: u16 = 28;
+const ATTR_UNENCODED_OFFSET: u16 = 29;
+const ATTR_COMPRESSION: u16 = 30;
+const ATTR_ENCRYPTION: u16 = 31;
+
+const FALLOC_FL_KEEP_SIZE: u64 = 1;
+const FALLOC_FL_PUNCH_HOLE: u64 = 2;
+
+const SECTOR: u64 = 4096;
+// the largest uncompressed extent the receiving side writes
+const MAX_EXTENT: u64 = 128 << 20;
+// default max_inline, smaller files written in one go end up inline
+const MAX_INLINE: u64 = 2048;
+// MAX_LFS_FILESIZE, nothing is written past it
+const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// the kernel's raw crc32c: seed 0 and no final inversion
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+static CRC32C: [u32; 256] = crc32c_table();
+
+pub fn crc32c(mut crc: u32, buf: &[u8]) -> u32 {
+    for &b in buf {
+        crc = CRC32C[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// one command with its attributes, which borrow the command buffer
+struct Command<'a> {
+    cmd: u16,
+    attrs: Vec<(u16, &'a [u8])>,
+}
+impl<'a> Command<'a> {
+    fn parse(cmd: u16, mut body: &'a [u8], version: u32) -> io::Result<Self> {
+        let mut attrs = Vec::new();
+        while !body.is_empty() {
+            let bad = || invalid(format!("Bad attribute in command {}", cmd));
+            let r#type = u16::from_le_bytes(body.get(..2).ok_or_else(bad)?.try_into().unwrap());
+            // from v2 on the data goes on to the end of the command, without a length
+            if r#type == ATTR_DATA && version >= 2 {
+                attrs.push((r#type, &body[2..]));
+                break;
+            }
+            let len = u16::from_le_bytes(body.get(2..4).ok_or_else(bad)?.try_into().unwrap());
+            let value = body.get(4..4 + len as usize).ok_or_else(bad)?;
+            attrs.push((r#type, value));
+            body = &body[4 + len as usize..];
+        }
+        Ok(Self { cmd, attrs })
+    }
+
+    fn get(&self, attr: u16) -> io::Result<&'a [u8]> {
+        self.attrs
+            .iter()
+            .find(|(t, _)| *t == attr)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| invalid(format!("Command {} misses attribute {}", self.cmd, attr)))
+    }
+
+    fn num(&self, attr: u16) -> io::Result<u64> {
+        let value = self.get(attr)?;
+        if value.len() > 8 {
+            return Err(invalid(format!("Attribute {} too long", attr)));
+        }
+        let mut ret = [0; 8];
+        ret[..value.len()].copy_from_slice(value);
+        Ok(u64::from_le_bytes(ret))
+    }
+
+    fn path(&self, attr: u16) -> io::Result<PathBuf> {
+        Ok(OsStr::from_bytes(self.get(attr)?).into())
+    }
+
+    fn uuid(&self, attr: u16) -> io::Result<[u8; 16]> {
+        self.get(attr)?
+            .try_into()
+            .map_err(|_| invalid(format!("Attribute {} isn't a uuid", attr)))
+    }
+}
+
+// sector aligned range, as the receiving side writes it
+fn sectors(pos: u64, len: u64) -> io::Result<(u64, u64)> {
+    match pos.checked_add(len) {
+        Some(end) if end <= MAX_FILE_SIZE => {
+            Ok((pos & !(SECTOR - 1), (end + SECTOR - 1) & !(SECTOR - 1)))
+        }
+        _ => Err(invalid(format!(
+            "Range at {} past the largest file size",
+            pos
+        ))),
+    }
+}
+
+#[derive(Debug, Default)]
+struct SendFile {
+    size: u64,
+    // file position -> the part of an extent found there, num_bytes long
+    pieces: BTreeMap<u64, FileExtentItem>,
+    // piece that consecutive writes still add to
+    open: Option<u64>,
+    // came from the parent of a snapshot, only what the stream changes is known
+    inherited: bool,
+}
+impl SendFile {
+    // cuts [start, end) out of the pieces
+    fn punch(&mut self, start: u64, end: u64) {
+        let cut: Vec<_> = self
+            .pieces
+            .range(..end)
+            .rev()
+            .take_while(|(pos, item)| *pos + item.num_bytes > start)
+            .map(|(pos, item)| (*pos, *item))
+            .collect();
+        for (pos, item) in cut {
+            self.pieces.remove(&pos);
+            if pos < start {
+                let mut left = item;
+                left.num_bytes = start - pos;
+                self.pieces.insert(pos, left);
+            }
+            if pos + item.num_bytes > end {
+                let mut right = item;
+                right.offset += end - pos;
+                right.num_bytes = pos + item.num_bytes - end;
+                self.pieces.insert(end, right);
+            }
+        }
+    }
+
+    // pieces of [start, end), cut to fit
+    fn range(&self, start: u64, end: u64) -> Vec<(u64, FileExtentItem)> {
+        let first = self
+            .pieces
+            .range(..start)
+            .next_back()
+            .filter(|(pos, item)| *pos + item.num_bytes > start);
+        first
+            .into_iter()
+            .chain(self.pieces.range(start..end))
+            .map(|(&pos, &item)| {
+                let mut item = item;
+                let from = pos.max(start);
+                let to = (pos + item.num_bytes).min(end);
+                item.offset += from - pos;
+                item.num_bytes = to - from;
+                (from, item)
+            })
+            .collect()
+    }
+
+    fn grow(&mut self, end: u64) {
+        self.size = self.size.max(end);
+    }
+}
+
+// a subvolume as the receiving side would create it
+#[derive(Debug)]
+struct Subvol {
+    path: PathBuf,
+    uuid: [u8; 16],
+    // incremental, the files of the parent snapshot are there without being made
+    snapshot: bool,
+    files: Vec<SendFile>,
+    // every link of every file
+    links: BTreeMap<PathBuf, usize>,
+}
+impl Subvol {
+    fn file(&mut self, cmd: &Command) -> io::Result<&mut SendFile> {
+        let path = cmd.path(ATTR_PATH)?;
+        let i = match self.links.get(&path) {
+            Some(&i) => i,
+            // a file of the parent shows up the first time the stream changes it
+            None if self.snapshot => {
+                self.links.insert(path, self.files.len());
+                self.files.push(SendFile {
+                    inherited: true,
+                    ..Default::default()
+                });
+                self.files.len() - 1
+            }
+            None => return Err(invalid(format!("{}: No such file", path.display()))),
+        };
+        Ok(&mut self.files[i])
+    }
+
+    // a directory takes everything below it along
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<_> = self
+            .links
+            .range(from.to_owned()..)
+            .take_while(|(path, _)| path.starts_with(from))
+            .map(|(path, &i)| (path.clone(), i))
+            .collect();
+        for (path, i) in moved {
+            self.links.remove(&path);
+            let path = match path.strip_prefix(from).unwrap() {
+                rest if rest.as_os_str().is_empty() => to.to_owned(),
+                rest => to.join(rest),
+            };
+            self.links.insert(path, i);
+        }
+    }
+}
+
+// builds the files of every subvolume in the stream
+#[derive(Debug, Default)]
+struct Receiver {
+    subvols: Vec<Subvol>,
+    // logical address for the next new extent, each gets room to grow to the maximum
+    next_bytenr: u64,
+    // extents outside the stream, by the subvolume, path and range they were cloned from
+    foreign: HashMap<([u8; 16], PathBuf, u64, u64), FileExtentItem>,
+}
+impl Receiver {
+    fn alloc(&mut self) -> u64 {
+        self.next_bytenr += MAX_EXTENT;
+        self.next_bytenr
+    }
+
+    fn subvol(&mut self) -> io::Result<&mut Subvol> {
+        self.subvols
+            .last_mut()
+            .ok_or_else(|| invalid("No subvolume to receive into".to_string()))
+    }
+
+    // a new extent referenced as a whole
+    fn fresh(&mut self, r#type: u8, comp: Compression, disk: u64, ram: u64) -> FileExtentItem {
+        FileExtentItem {
+            ram_bytes: ram,
+            compression: comp as u8,
+            r#type,
+            disk_bytenr: self.alloc(),
+            disk_num_bytes: disk,
+            num_bytes: ram,
+            ..Default::default()
+        }
+    }
+
+    fn command(&mut self, cmd: &Command) -> io::Result<()> {
+        match cmd.cmd {
+            CMD_SUBVOL | CMD_SNAPSHOT => self.subvols.push(Subvol {
+                path: cmd.path(ATTR_PATH)?,
+                uuid: cmd.uuid(ATTR_UUID)?,
+                snapshot: cmd.cmd == CMD_SNAPSHOT,
+                files: Vec::new(),
+                links: BTreeMap::new(),
+            }),
+            CMD_MKFILE => {
+                let subvol = self.subvol()?;
+                subvol
+                    .links
+                    .insert(cmd.path(ATTR_PATH)?, subvol.files.len());
+                subvol.files.push(SendFile::default());
+            }
+            CMD_RENAME => {
+                let (from, to) = (cmd.path(ATTR_PATH)?, cmd.path(ATTR_PATH_TO)?);
+                self.subvol()?.rename(&from, &to);
+            }
+            CMD_LINK => {
+                let subvol = self.subvol()?;
+                let target = cmd.path(ATTR_PATH_LINK)?;
+                if let Some(&i) = subvol.links.get(&target) {
+                    subvol.links.insert(cmd.path(ATTR_PATH)?, i);
+                }
+            }
+            CMD_UNLINK => {
+                let path = cmd.path(ATTR_PATH)?;
+                self.subvol()?.links.remove(&path);
+            }
+            CMD_WRITE | CMD_UPDATE_EXTENT => {
+                let pos = cmd.num(ATTR_FILE_OFFSET)?;
+                let len = match cmd.cmd {
+                    CMD_WRITE => cmd.get(ATTR_DATA)?.len() as u64,
+                    _ => cmd.num(ATTR_SIZE)?,
+                };
+                let (start, end) = sectors(pos, len)?;
+                let file = self.subvol()?.file(cmd)?;
+                // where the open piece ended before the write cut into it
+                let open_end = file
+                    .open
+                    .and_then(|at| Some(at + file.pieces.get(&at)?.num_bytes));
+                file.punch(start, end);
+                file.grow(pos + len);
+                // buffered writes that follow each other end up in one extent, a sector
+                // both of them wrote to is only in there once
+                if let Some((at, open)) = file
+                    .open
+                    .and_then(|at| Some((at, file.pieces.get_mut(&at)?)))
+                {
+                    let ram = open.num_bytes + (end - start);
+                    if at + open.num_bytes == start && open_end <= Some(end) && ram <= MAX_EXTENT {
+                        open.num_bytes = ram;
+                        open.ram_bytes = ram;
+                        open.disk_num_bytes = ram;
+                        return Ok(());
+                    }
+                }
+                let item = self.fresh(
+                    BTRFS_FILE_EXTENT_REG,
+                    Compression::None,
+                    end - start,
+                    end - start,
+                );
+                let file = self.subvol()?.file(cmd)?;
+                file.pieces.insert(start, item);
+                file.open = Some(start);
+            }
+            CMD_ENCODED_WRITE => {
+                if cmd.num(ATTR_ENCRYPTION).unwrap_or(0) != 0 {
+                    return Err(invalid("Encrypted writes aren't supported".to_string()));
+                }
+                let comp = match cmd.num(ATTR_COMPRESSION)? {
+                    0 => Compression::None,
+                    1 => Compression::Zlib,
+                    2 => Compression::Zstd,
+                    3..=7 => Compression::Lzo,
+                    n => return Err(invalid(format!("Unknown compression {}", n))),
+                };
+                let pos = cmd.num(ATTR_FILE_OFFSET)?;
+                let file_len = cmd.num(ATTR_UNENCODED_FILE_LEN)?;
+                let (ram, offset) = (
+                    cmd.num(ATTR_UNENCODED_LEN)?,
+                    cmd.num(ATTR_UNENCODED_OFFSET)?,
+                );
+                // the file range has to lie inside a non-empty extent, as the kernel checks
+                let outside = offset.checked_add(file_len).is_none_or(|e| e > ram);
+                if ram == 0 || ram > MAX_EXTENT || outside {
+                    return Err(invalid(format!("Bad encoded write at {}", pos)));
+                }
+                let disk = sectors(0, cmd.get(ATTR_DATA)?.len() as u64)?.1;
+                let mut item = self.fresh(BTRFS_FILE_EXTENT_REG, comp, disk, ram);
+                let (start, end) = sectors(pos, file_len)?;
+                item.offset = offset;
+                item.num_bytes = end - start;
+                let file = self.subvol()?.file(cmd)?;
+                file.open = None;
+                file.punch(start, end);
+                file.grow(pos + file_len);
+                file.pieces.insert(start, item);
+            }
+            CMD_CLONE => {
+                let pos = cmd.num(ATTR_FILE_OFFSET)?;
+                let len = cmd.num(ATTR_CLONE_LEN)?;
+                let src_pos = cmd.num(ATTR_CLONE_OFFSET)?;
+                let (src_uuid, src_path) = (cmd.uuid(ATTR_CLONE_UUID)?, cmd.path(ATTR_CLONE_PATH)?);
+                let (src_start, src_end) = sectors(src_pos, len)?;
+                let source = self
+                    .subvols
+                    .iter_mut()
+                    .rev()
+                    .find(|s| s.uuid == src_uuid)
+                    .and_then(|s| Some(&mut s.files[*s.links.get(&src_path)?]));
+                let pieces = match source {
+                    Some(source) => {
+                        source.open = None;
+                        source.range(src_start, src_end)
+                    }
+                    // from a parent snapshot, whatever it holds there is shared
+                    None => {
+                        let key = (src_uuid, src_path, src_start, src_end);
+                        let item = match self.foreign.get(&key) {
+                            Some(item) => *item,
+                            None => self.fresh(
+                                BTRFS_FILE_EXTENT_REG,
+                                Compression::None,
+                                src_end - src_start,
+                                src_end - src_start,
+                            ),
+                        };
+                        self.foreign.insert(key, item);
+                        vec![(src_start, item)]
+                    }
+                };
+                let (start, end) = sectors(pos, len)?;
+                let file = self.subvol()?.file(cmd)?;
+                file.open = None;
+                file.punch(start, end);
+                file.grow(pos + len);
+                for (at, item) in pieces {
+                    file.pieces.insert(at - src_start + start, item);
+                }
+            }
+            CMD_TRUNCATE => {
+                let size = cmd.num(ATTR_SIZE)?;
+                let (_, end) = sectors(size, 0)?;
+                let file = self.subvol()?.file(cmd)?;
+                file.open = None;
+                file.punch(end, u64::MAX);
+                file.size = size;
+            }
+            CMD_FALLOCATE => {
+                let mode = cmd.num(ATTR_FALLOCATE_MODE)?;
+                let pos = cmd.num(ATTR_FILE_OFFSET)?;
+                let len = cmd.num(ATTR_SIZE)?;
+                let (start, end) = sectors(pos, len)?;
+                let file = self.subvol()?.file(cmd)?;
+                file.open = None;
+                if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+                    file.punch(start, end);
+                    return Ok(());
+                }
+                if mode & FALLOC_FL_KEEP_SIZE == 0 {
+                    file.grow(pos + len);
+                }
+                // only the gaps get preallocated
+                let mut gaps = Vec::new();
+                let mut at = start;
+                for (pos, item) in file.range(start, end) {
+                    if pos > at {
+                        gaps.push((at, pos));
+                    }
+                    at = pos + item.num_bytes;
+                }
+                if at < end {
+                    gaps.push((at, end));
+                }
+                for (start, end) in gaps {
+                    let item = self.fresh(
+                        BTRFS_FILE_EXTENT_PREALLOC,
+                        Compression::None,
+                        end - start,
+                        end - start,
+                    );
+                    self.subvol()?.file(cmd)?.pieces.insert(start, item);
+                }
+            }
+            // metadata, directories and special files don't add to the stats
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// returns false at the end of the input
+fn read_stream(r: &mut impl Read, recv: &mut Receiver, quit_sig: &AtomicBool) -> io::Result<bool> {
+    let mut header = [0; 17];
+    match r.read_exact(&mut header) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        ret => ret?,
+    }
+    if &header[..13] != MAGIC {
+        return Err(invalid("Not a send stream".to_string()));
+    }
+    let version = u32::from_le_bytes(header[13..].try_into().unwrap());
+    if !(1..=3).contains(&version) {
+        return Err(invalid(format!(
+            "Unsupported send stream version {}",
+            version
+        )));
+    }
+    let max_size = if version == 1 {
+        MAX_CMD_SIZE_V1
+    } else {
+        MAX_CMD_SIZE_V2
+    };
+    let mut buf = Vec::new();
+    while !quit_sig.load(Ordering::Acquire) {
+        buf.resize(CMD_HEADER_SIZE, 0);
+        r.read_exact(&mut buf)?;
+        let len = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        let cmd = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+        let crc = u32::from_le_bytes(buf[6..10].try_into().unwrap());
+        buf[6..10].fill(0);
+        if len > max_size - CMD_HEADER_SIZE {
+            return Err(invalid(format!("Command {} too long ({})", cmd, len)));
+        }
+        buf.resize(CMD_HEADER_SIZE + len, 0);
+        r.read_exact(&mut buf[CMD_HEADER_SIZE..])?;
+        if crc32c(0, &buf) != crc {
+            return Err(invalid(format!("Checksum mismatch in command {}", cmd)));
+        }
+        if cmd == CMD_END {
+            return Ok(true);
+        }
+        let cmd = Command::parse(cmd, &buf[CMD_HEADER_SIZE..], version)?;
+        recv.command(&cmd)?;
+    }
+    Ok(false)
+}
+
+fn read(path: &Path, recv: &mut Receiver, quit_sig: &AtomicBool) -> io::Result<()> {
+    let input: Box<dyn Read> = if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut r = BufReader::new(input);
+    // streams of several subvolumes follow each other
+    while read_stream(&mut r, recv, quit_sig)? {}
+    Ok(())
+}
+
+// the files a send stream would create on the receiving side, "-" reads stdin
+pub(crate) fn walk(path: &Path, quit_sig: &AtomicBool, tx: WorkerTx) -> WalkInfo {
+    let mut info = WalkInfo::default();
+    let mut recv = Receiver::default();
+    if let Err(e) = read(path, &mut recv, quit_sig) {
+        quit_sig.store(true, Ordering::Release);
+        eprintln!("{}: {}", path.display(), e);
+        return info;
+    }
+    // all subvolumes end up on the same filesystem
+    let fsid = Fsid(recv.subvols.first().map_or([0; 16], |s| s.uuid));
+    for (i, subvol) in recv.subvols.into_iter().enumerate() {
+        let loc = Location {
+            fsid,
+            subvol: BTRFS_FIRST_FREE_OBJECTID + i as u64,
+        };
+        info.subvol_paths.insert(loc, subvol.path.clone());
+        let mut names = HashMap::new();
+        for (path, i) in subvol.links {
+            names.entry(i).or_insert(path);
+        }
+        for (ino, file) in subvol.files.into_iter().enumerate() {
+            if quit_sig.load(Ordering::Acquire) {
+                return info;
+            }
+            // unlinked on the way
+            let Some(name) = names.remove(&ino) else {
+                continue;
+            };
+            let ino = BTRFS_FIRST_FREE_OBJECTID + 1 + ino as u64;
+            let job = FileJob {
+                path: subvol.path.join(name),
+                ino,
+                size: file.size,
+                loc,
+                fd: None,
+                group: 0,
+                partial: file.inherited,
+                items: Some(items(ino, file)),
+            };
+            tx.send(job).unwrap();
+        }
+    }
+    info
+}
+
+fn items(ino: u64, file: SendFile) -> Vec<IoctlSearchItem> {
+    let mut pieces: Vec<_> = file.pieces.into_iter().collect();
+    // the size of an inherited file isn't known, small writes may go anywhere in it
+    if let ([(0, item)], false) = (&mut pieces[..], file.inherited) {
+        let whole = item.offset == 0 && item.num_bytes == item.ram_bytes;
+        if whole
+            && file.size <= MAX_INLINE
+            && item.r#type == BTRFS_FILE_EXTENT_REG
+            && item.compression == Compression::None as u8
+        {
+            item.r#type = BTRFS_FILE_EXTENT_INLINE;
+            item.ram_bytes = file.size;
+            let len = 21 + file.size as u32;
+            return vec![IoctlSearchItem::extent_data(ino, 0, len, *item)];
+        }
+    }
+    pieces
+        .into_iter()
+        .map(|(pos, item)| IoctlSearchItem::extent_data(ino, pos, 53, item))
+        .collect()
+}
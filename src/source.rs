@@ -0,0 +1,166 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use crate::{
+    btrfs::{self, Extent, Fsid, IoctlSearchItem, Sv2Args},
+    cache, fiemap, record, FileJob, Location, ScanStats, Since,
+};
+
+// where the extents of a file come from, errors are ready to print after the path
+pub(crate) trait ExtentSource: Send {
+    fn extents(&mut self, job: &mut FileJob, out: &mut Vec<Extent>) -> Result<(), String>;
+    // hand over what the next run may use
+    fn finish(self: Box<Self>, _stats: &mut ScanStats) {}
+}
+
+// exact extents from the btrfs trees, needs CAP_SYS_ADMIN
+pub(crate) struct TreeSearch<'map> {
+    sv2_arg: Sv2Args,
+    since: Since,
+    // results of the previous run, reused for unchanged inodes
+    old_cache: Option<&'map cache::Cache>,
+    cache: cache::Cache,
+    // generation of every filesystem when its first file was searched
+    generations: HashMap<Fsid, u64>,
+    // per subvolume, the inodes changed since the old cache was written, None if it
+    // holds nothing of the subvolume
+    changed: HashMap<Location, Option<HashSet<u64>>>,
+    // keep the raw results for --record
+    record: bool,
+    recorded: Vec<record::FileRecord>,
+    items: Vec<IoctlSearchItem>,
+}
+impl<'map> TreeSearch<'map> {
+    pub(crate) fn new(since: Since, old_cache: Option<&'map cache::Cache>, record: bool) -> Self {
+        Self {
+            sv2_arg: Sv2Args::new(),
+            since,
+            old_cache,
+            cache: cache::Cache::default(),
+            generations: HashMap::new(),
+            changed: HashMap::new(),
+            record,
+            recorded: Vec::new(),
+            items: Vec::new(),
+        }
+    }
+
+    fn read_items(&mut self, job: &mut FileJob) -> rustix::io::Result<()> {
+        let items = &mut self.items;
+        if let Some(found) = job.items.take() {
+            *items = found;
+            return Ok(());
+        }
+        // the file itself is never opened
+        let file = job.fd.as_deref().expect("nothing to search through");
+        let ino = job.ino;
+        if let Some(old_cache) = self.old_cache {
+            let loc = job.loc;
+            // read before searching, whatever is written meanwhile is newer
+            let generation = match self.generations.get(&loc.fsid) {
+                Some(&generation) => generation,
+                None => {
+                    let generation = btrfs::fs_info(file)?.generation;
+                    self.generations.insert(loc.fsid, generation);
+                    generation
+                }
+            };
+            // one search of the leaves written since then for the whole subvolume
+            let changed = match self.changed.entry(loc) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(match old_cache.generations.get(&loc) {
+                    Some(&since) => Some(self.sv2_arg.changed_inodes(file, since)?),
+                    None => None,
+                }),
+            };
+            let key = cache::CacheKey { loc, ino };
+            match (old_cache.files.get(&key), changed) {
+                (Some(cached), Some(changed)) if !changed.contains(&ino) => {
+                    items.extend_from_slice(cached)
+                }
+                _ => items.extend(self.sv2_arg.search_file(file, ino)?),
+            }
+            self.cache.files.insert(key, items.clone());
+            let cached_at = self.cache.generations.entry(loc).or_insert(generation);
+            *cached_at = (*cached_at).min(generation);
+            return Ok(());
+        }
+        let since = self.since.get(job.loc.fsid);
+        self.sv2_arg
+            .set_min_transid(if since == 0 { 0 } else { since + 1 });
+        let mut iter = self.sv2_arg.search_file(file, ino)?;
+        if !self.record {
+            items.extend(iter);
+            return Ok(());
+        }
+        iter = iter.record();
+        items.extend(iter.by_ref());
+        self.recorded.push(record::FileRecord {
+            path: job.path.clone(),
+            ino,
+            size: job.size,
+            loc: job.loc,
+            group: job.group,
+            batches: iter.into_batches(),
+        });
+        Ok(())
+    }
+}
+impl ExtentSource for TreeSearch<'_> {
+    fn extents(&mut self, job: &mut FileJob, out: &mut Vec<Extent>) -> Result<(), String> {
+        self.items.clear();
+        match self.read_items(job) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == 25 => {
+                return Err("Not btrfs (or tree search unsupported)".to_string())
+            }
+            Err(e) => return Err(format!("SEARCH_V2: {}", e)),
+        }
+        for item in &self.items {
+            out.push(item.parse()?);
+        }
+        Ok(())
+    }
+    fn finish(self: Box<Self>, stats: &mut ScanStats) {
+        stats.cache = self.cache;
+        stats.recorded = self.recorded;
+    }
+}
+
+// estimates for any filesystem that supports FIEMAP, no privileges needed
+pub(crate) struct Fiemap;
+impl ExtentSource for Fiemap {
+    fn extents(&mut self, job: &mut FileJob, out: &mut Vec<Extent>) -> Result<(), String> {
+        fiemap::read(&job.path, job.size, out).map_err(|e| format!("FIEMAP: {}", e))
+    }
+}
+
+// files kept in memory, keyed by path, searched like the real trees, for tests
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct Mock {
+    pub(crate) files: HashMap<std::path::PathBuf, btrfs::MockTree>,
+    sv2_arg: Box<Sv2Args>,
+}
+#[cfg(test)]
+impl Default for Mock {
+    fn default() -> Self {
+        Self {
+            files: HashMap::new(),
+            sv2_arg: Box::new(Sv2Args::new()),
+        }
+    }
+}
+#[cfg(test)]
+impl ExtentSource for Mock {
+    fn extents(&mut self, job: &mut FileJob, out: &mut Vec<Extent>) -> Result<(), String> {
+        let tree = self.files.get(&job.path).ok_or("No such file")?;
+        let items = self
+            .sv2_arg
+            .search_file(tree, job.ino)
+            .map_err(|e| format!("SEARCH_V2: {}", e))?;
+        for item in items {
+            out.push(item.parse()?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::{
+    btrfs::{Extent, ExtentType},
+    scale::Scale,
+};
+
+// feed the extents of one file in file offset order, gaps between them are holes too
+#[derive(Debug, Default)]
+pub struct FileHoles {
+    end: u64,
+    in_hole: bool,
+    nhole: u64,
+    bytes: u64,
+}
+impl FileHoles {
+    pub fn add(&mut self, ext: &Extent) {
+        if ext.pos > self.end {
+            self.add_hole(ext.pos - self.end);
+        }
+        if ext.key.r#type() == ExtentType::Hole {
+            self.add_hole(ext.stat.refd);
+        } else {
+            self.in_hole = false;
+        }
+        self.end = self.end.max(ext.pos + ext.stat.refd);
+    }
+    fn add_hole(&mut self, len: u64) {
+        if !self.in_hole {
+            self.nhole += 1;
+        }
+        self.in_hole = true;
+        self.bytes += len;
+    }
+    // returns the number of holes and their bytes
+    pub fn finish(mut self, size: u64) -> (u64, u64) {
+        if size > self.end {
+            self.add_hole(size - self.end);
+        }
+        (self.nhole, self.bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SparseFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hole: u64,
+}
+
+pub struct SparseDisplay<'a> {
+    pub top: &'a [SparseFile],
+    pub scale: Scale,
+}
+impl Display for SparseDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Most sparse files:")?;
+        writeln!(f, "{:>12} {:>12} {:>6}  Path", "Size", "Holes", "Sparse")?;
+        for file in self.top {
+            writeln!(
+                f,
+                "{:>12} {:>12} {:>5.1}%  {}",
+                self.scale.scale(file.size),
+                self.scale.scale(file.hole),
+                file.hole as f64 * 100.0 / file.size.max(1) as f64,
+                file.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use crate::btrfs::Fsid;
+
+// the state file holds one "<fsid> <generation>" line per filesystem
+
+pub fn load(path: &Path) -> io::Result<BTreeMap<Fsid, u64>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // first run, scan everything
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut ret = BTreeMap::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, format!("Invalid line: {}", line));
+        let (fsid, gen) = line.trim().split_once(' ').ok_or_else(invalid)?;
+        let fsid = fsid.parse().map_err(|_| invalid())?;
+        let gen = gen.trim().parse().map_err(|_| invalid())?;
+        ret.insert(fsid, gen);
+    }
+    Ok(ret)
+}
+
+// filesystems not scanned this time keep their old generation
+pub fn save(
+    path: &Path,
+    mut state: BTreeMap<Fsid, u64>,
+    scanned: &BTreeMap<Fsid, u64>,
+) -> io::Result<()> {
+    state.extend(scanned.iter().filter(|(_, &gen)| gen != 0));
+    let content: String = state
+        .iter()
+        .map(|(fsid, gen)| format!("{} {}\n", fsid, gen))
+        .collect();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,1471 @@
+use super::*;
+use btrfs::{
+    Compression, ExtentType, FileExtentItem, IoctlSearchItem, BTRFS_FILE_EXTENT_INLINE,
+    BTRFS_FILE_EXTENT_PREALLOC, BTRFS_FILE_EXTENT_REG,
+};
+use proptest::prelude::*;
+use std::fs;
+
+const KIB: u64 = 1024;
+
+fn extent(r#type: u8, comp: Compression, bytenr: u64, disk: u64, ram: u64) -> FileExtentItem {
+    FileExtentItem {
+        generation: 10,
+        ram_bytes: ram,
+        compression: comp as u8,
+        r#type,
+        disk_bytenr: bytenr,
+        disk_num_bytes: disk,
+        num_bytes: ram,
+        ..Default::default()
+    }
+}
+
+fn regular(pos: u64, comp: Compression, bytenr: u64, disk: u64, ram: u64) -> IoctlSearchItem {
+    let item = extent(BTRFS_FILE_EXTENT_REG, comp, bytenr, disk, ram);
+    IoctlSearchItem::extent_data(257, pos, 53, item)
+}
+
+// a reference to part of an extent
+fn partial(pos: u64, bytenr: u64, disk: u64, offset: u64, num: u64) -> IoctlSearchItem {
+    let mut item = extent(BTRFS_FILE_EXTENT_REG, Compression::None, bytenr, disk, disk);
+    item.offset = offset;
+    item.num_bytes = num;
+    IoctlSearchItem::extent_data(257, pos, 53, item)
+}
+
+fn prealloc(pos: u64, bytenr: u64, len: u64) -> IoctlSearchItem {
+    let item = extent(
+        BTRFS_FILE_EXTENT_PREALLOC,
+        Compression::None,
+        bytenr,
+        len,
+        len,
+    );
+    IoctlSearchItem::extent_data(257, pos, 53, item)
+}
+
+// `data` bytes of inline data follow the 21 byte header
+fn inline(comp: Compression, data: u64, ram: u64) -> IoctlSearchItem {
+    let item = extent(BTRFS_FILE_EXTENT_INLINE, comp, 0, 0, ram);
+    IoctlSearchItem::extent_data(257, 0, 21 + data as u32, item)
+}
+
+fn hole(pos: u64, len: u64) -> IoctlSearchItem {
+    regular(pos, Compression::None, 0, 0, len)
+}
+
+struct Scan {
+    stats: ScanStats,
+    extents: Extents,
+    quit: bool,
+}
+impl Scan {
+    fn total(&self) -> CompsizeStat {
+        let mut ret = CompsizeStat::default();
+        for stat in self.stats.fs.values() {
+            ret.merge(stat.clone());
+        }
+        ret
+    }
+}
+
+const LOC: Location = Location {
+    fsid: Fsid([1; 16]),
+    subvol: btrfs::BTRFS_FS_TREE_OBJECTID,
+};
+
+// every file is (path, size, argument index, items), all in one subvolume
+fn scan(opts: &Opts, files: Vec<(&str, u64, u32, Vec<IoctlSearchItem>)>) -> Scan {
+    let files = files
+        .into_iter()
+        .map(|(path, size, group, items)| (path, size, group, LOC, items))
+        .collect();
+    scan_at(opts, files)
+}
+
+fn scan_at(opts: &Opts, files: Vec<(&str, u64, u32, Location, Vec<IoctlSearchItem>)>) -> Scan {
+    let mut mock = source::Mock::default();
+    let mut jobs = Vec::new();
+    // every file is a tree of its own, holding the items of one inode
+    for (path, size, group, loc, items) in files {
+        let ino = items.first().map_or(257, IoctlSearchItem::objectid);
+        let tree = btrfs::MockTree {
+            items,
+            ..Default::default()
+        };
+        mock.files.insert(path.into(), tree);
+        let job = FileJob {
+            path: path.into(),
+            ino,
+            size,
+            loc,
+            fd: None,
+            group,
+            items: None,
+            partial: false,
+        };
+        jobs.push(job);
+    }
+    run(opts, Box::new(mock), jobs)
+}
+
+fn run(opts: &Opts, source: Box<dyn ExtentSource>, jobs: Vec<FileJob>) -> Scan {
+    let (tx, rx) = unbounded();
+    let extents = Extents::default();
+    let quit_sig = AtomicBool::new(false);
+    for job in jobs {
+        tx.send(job).unwrap();
+    }
+    drop(tx);
+    let worker = Worker::new(rx, opts, Since::default(), source, &extents, &quit_sig);
+    let stats = worker.run();
+    let quit = quit_sig.load(Ordering::Acquire);
+    Scan {
+        stats,
+        extents,
+        quit,
+    }
+}
+
+#[test]
+fn compressed() {
+    let files = vec![(
+        "a",
+        128 * KIB,
+        0,
+        vec![regular(0, Compression::Zstd, 1 << 20, 32 * KIB, 128 * KIB)],
+    )];
+    let total = scan(&Opts::default(), files).total();
+    assert_eq!((total.nfile, total.nextent, total.nref), (1, 1, 1));
+    let zstd = total.stat[Compression::Zstd.as_usize()];
+    assert_eq!(
+        zstd,
+        ExtentStat {
+            disk: 32 * KIB,
+            uncomp: 128 * KIB,
+            refd: 128 * KIB
+        }
+    );
+    assert_eq!(zstd.get_percent(), 25);
+    let nothing_stored = ExtentStat {
+        disk: 4 * KIB,
+        ..Default::default()
+    };
+    assert_eq!(nothing_stored.get_percent(), 0);
+}
+
+#[test]
+fn shared() {
+    let bytenr = 1 << 20;
+    let files = vec![
+        (
+            "a",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::None, bytenr, 64 * KIB, 64 * KIB)],
+        ),
+        (
+            "b",
+            8 * KIB,
+            1,
+            vec![partial(0, bytenr, 64 * KIB, 4 * KIB, 8 * KIB)],
+        ),
+    ];
+    let opts = Opts {
+        du: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let total = scan.total();
+    assert_eq!((total.nfile, total.nextent, total.nref), (2, 1, 2));
+    let none = total.stat[Compression::None.as_usize()];
+    assert_eq!((none.disk, none.refd), (64 * KIB, 72 * KIB));
+    let entry = scan.extents.map.iter().next().unwrap();
+    let refs = entry.value().as_deref().unwrap();
+    assert_eq!((refs.nref, refs.refd), (2, 72 * KIB));
+    let du = du::collect(&scan.extents.map, &[0, 0]);
+    assert_eq!((du[0].total, du[0].exclusive), (64 * KIB, 0));
+    assert_eq!((du[1].total, du[1].exclusive), (64 * KIB, 0));
+}
+
+#[test]
+fn by_subvolume() {
+    let bytenr = 1 << 20;
+    let files = vec![
+        (
+            "a",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::Zstd, bytenr, 16 * KIB, 64 * KIB)],
+        ),
+        (
+            "b",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::Zstd, bytenr, 16 * KIB, 64 * KIB)],
+        ),
+    ];
+    let opts = Opts {
+        by_subvolume: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let subvol = scan.stats.subvol.values().next().unwrap();
+    assert_eq!((subvol.nfile, subvol.nextent, subvol.nref), (2, 1, 2));
+    assert_eq!(subvol.stat[Compression::Zstd.as_usize()].disk, 16 * KIB);
+    // only the filesystem wide entry is kept, and no report asks for its details
+    assert_eq!(scan.extents.map.len(), 1);
+    assert!(scan.extents.map.iter().next().unwrap().value().is_none());
+    assert_eq!(scan.extents.fs.fsids(), vec![Fsid([1; 16])]);
+}
+
+#[test]
+fn two_filesystems() {
+    let other = Location {
+        fsid: Fsid([2; 16]),
+        ..LOC
+    };
+    let item = || vec![regular(0, Compression::Zlib, 1 << 20, 16 * KIB, 64 * KIB)];
+    let files = vec![
+        ("a", 64 * KIB, 0, LOC, item()),
+        ("b", 64 * KIB, 0, LOC, item()),
+        ("c", 64 * KIB, 1, other, item()),
+    ];
+    let scan = scan_at(&Opts::default(), files);
+    // the same bytenr on another filesystem is another extent
+    assert_eq!(scan.extents.map.len(), 2);
+    assert_eq!(scan.stats.fs.len(), 2);
+    let one = &scan.stats.fs[&LOC.fsid];
+    assert_eq!((one.nfile, one.nextent, one.nref), (2, 1, 2));
+    let two = &scan.stats.fs[&other.fsid];
+    assert_eq!((two.nfile, two.nextent, two.nref), (1, 1, 1));
+    assert_eq!(two.stat[Compression::Zlib.as_usize()].disk, 16 * KIB);
+    let total = scan.total();
+    assert_eq!(total.stat[Compression::Zlib.as_usize()].disk, 32 * KIB);
+}
+
+#[test]
+fn inline_extent() {
+    let files = vec![("a", 100, 0, vec![inline(Compression::Zlib, 50, 100)])];
+    let scan = scan(&Opts::default(), files);
+    let total = scan.total();
+    assert_eq!((total.ninline, total.nextent, total.nref), (1, 0, 0));
+    let zlib = total.stat[Compression::Zlib.as_usize()];
+    assert_eq!((zlib.disk, zlib.uncomp, zlib.refd), (50, 100, 100));
+    assert_eq!(scan.stats.inline, vec![50]);
+    assert!(scan.extents.map.is_empty());
+}
+
+#[test]
+fn prealloc_extent() {
+    let files = vec![(
+        "a",
+        1024 * KIB,
+        0,
+        vec![
+            regular(0, Compression::None, 1 << 20, 4 * KIB, 4 * KIB),
+            prealloc(4 * KIB, 2 << 20, 1020 * KIB),
+        ],
+    )];
+    let total = scan(&Opts::default(), files).total();
+    assert_eq!((total.nextent, total.nref), (2, 2));
+    assert_eq!(total.prealloc.disk, 1020 * KIB);
+    assert_eq!(total.stat[Compression::None.as_usize()].disk, 4 * KIB);
+}
+
+#[test]
+fn holes() {
+    // an explicit hole, then a gap of a NO_HOLES filesystem right after it, then the tail
+    let files = vec![(
+        "a",
+        1024 * KIB,
+        0,
+        vec![
+            regular(0, Compression::None, 1 << 20, 64 * KIB, 64 * KIB),
+            hole(64 * KIB, 64 * KIB),
+            regular(256 * KIB, Compression::None, 2 << 20, 64 * KIB, 64 * KIB),
+        ],
+    )];
+    let opts = Opts {
+        sparse: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let total = scan.total();
+    assert_eq!((total.nhole, total.hole), (2, 896 * KIB));
+    assert_eq!((total.nextent, total.nref), (2, 2));
+    assert_eq!(scan.stats.sparse_top[0].hole, 896 * KIB);
+}
+
+#[test]
+fn multi_batch() {
+    // more items than fit in one SEARCH_V2 buffer
+    let items: Vec<_> = (0..2000)
+        .map(|i| {
+            regular(
+                i * 4 * KIB,
+                Compression::None,
+                (i + 1) << 20,
+                4 * KIB,
+                4 * KIB,
+            )
+        })
+        .collect();
+    let tree = btrfs::MockTree {
+        items: items.clone(),
+        ..Default::default()
+    };
+    let mut sv2_arg = btrfs::Sv2Args::new();
+    let found: Vec<_> = sv2_arg.search_file(&tree, 257).unwrap().collect();
+    assert!(tree.nsearch.get() >= 3);
+    let offsets = |items: &[IoctlSearchItem]| {
+        items
+            .iter()
+            .map(|i| i.parse().unwrap().pos)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(offsets(&found), offsets(&items));
+
+    let total = scan(&Opts::default(), vec![("a", 8000 * KIB, 0, items)]).total();
+    assert_eq!((total.nextent, total.nref, total.nhole), (2000, 2000, 0));
+    assert_eq!(total.stat[0].disk, 8000 * KIB);
+}
+
+#[test]
+fn bad_item_stops_the_scan() {
+    let item = extent(
+        BTRFS_FILE_EXTENT_REG,
+        Compression::None,
+        1 << 20,
+        4096,
+        4096,
+    );
+    let files = vec![(
+        "a",
+        4096,
+        0,
+        vec![IoctlSearchItem::extent_data(257, 0, 40, item)],
+    )];
+    let scan = scan(&Opts::default(), files);
+    assert!(scan.quit);
+    assert!(scan.stats.fs.is_empty());
+}
+
+// a file name without any directory, relative to the crate root tests run in
+#[test]
+fn bare_file_name() {
+    let entry = WalkDir::new("Cargo.toml")
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    let mut locations = HashMap::new();
+    let ((_, fd), info) = lookup_location(&entry, &mut locations, true).unwrap();
+    assert!(info.is_some());
+    assert!(fd.metadata().unwrap().is_dir());
+}
+
+#[test]
+fn display() {
+    let files = vec![
+        (
+            "a",
+            128 * KIB,
+            0,
+            vec![regular(0, Compression::Zstd, 1 << 20, 32 * KIB, 128 * KIB)],
+        ),
+        (
+            "b",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::None, 2 << 20, 64 * KIB, 64 * KIB)],
+        ),
+        ("c", 100, 0, vec![inline(Compression::None, 100, 100)]),
+    ];
+    let total = scan(&Opts::default(), files).total();
+    let expected = "\
+Processed 3 files, 2 regular extents (2 refs), 1 inline.
+Type       Perc     Disk Usage   Uncompressed Referenced
+TOTAL       50%     96 KiB       192 KiB      192 KiB
+none       100%     64 KiB       64 KiB       64 KiB
+zstd        25%     32 KiB       128 KiB      128 KiB
+";
+    assert_eq!(trimmed(total.display(Scale::default())), expected);
+}
+
+// columns are padded to their width
+fn trimmed(output: impl Display) -> String {
+    output
+        .to_string()
+        .lines()
+        .map(|l| format!("{}\n", l.trim_end()))
+        .collect()
+}
+
+#[test]
+fn diff() {
+    let shared = || regular(0, Compression::None, 1 << 20, 64 * KIB, 64 * KIB);
+    let files = vec![
+        (
+            "a",
+            128 * KIB,
+            0,
+            vec![
+                shared(),
+                regular(64 * KIB, Compression::Zstd, 2 << 20, 16 * KIB, 64 * KIB),
+            ],
+        ),
+        (
+            "b",
+            96 * KIB,
+            1,
+            vec![
+                shared(),
+                regular(64 * KIB, Compression::None, 3 << 20, 32 * KIB, 32 * KIB),
+            ],
+        ),
+    ];
+    let opts = Opts {
+        diff: true,
+        top: 10,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let stat = diff::DiffStat::collect(&scan.extents.map);
+    let expected = "\
+Disk Usage Only in A    Only in B    Shared
+TOTAL      16 KiB       32 KiB       64 KiB
+none       0B           32 KiB       64 KiB
+zstd       16 KiB       0B           0B
+
+Uncompressed Only in A    Only in B    Shared
+TOTAL      64 KiB       32 KiB       64 KiB
+none       0B           32 KiB       64 KiB
+zstd       64 KiB       0B           0B
+";
+    assert_eq!(trimmed(stat.display(Scale::default())), expected);
+    let [a, b] = diff::top_files(&scan.extents.map, scan.stats.files, 10);
+    assert_eq!(a, vec![(PathBuf::from("a"), 16 * KIB)]);
+    assert_eq!(b, vec![(PathBuf::from("b"), 32 * KIB)]);
+}
+
+#[test]
+fn ref_histogram() {
+    let shared = || regular(0, Compression::None, 1 << 20, 32 * KIB, 32 * KIB);
+    let files = vec![
+        ("a", 32 * KIB, 0, vec![shared()]),
+        ("b", 32 * KIB, 0, vec![shared()]),
+        ("c", 32 * KIB, 0, vec![shared()]),
+        (
+            "d",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::None, 2 << 20, 64 * KIB, 64 * KIB)],
+        ),
+    ];
+    let opts = Opts {
+        ref_histogram: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let hist = hist::RefHistogram::collect(&scan.extents.map);
+    let expected = "\
+Refs       Extents      Disk Usage   Referenced
+1                     1 64 KiB       64 KiB
+3-10                  1 32 KiB       96 KiB
+";
+    assert_eq!(trimmed(hist.display(Scale::default())), expected);
+}
+
+#[test]
+fn add_range() {
+    let mut ranges = Vec::new();
+    for range in [(0, 4), (8, 12), (20, 24), (2, 9)] {
+        bookend::add_range(&mut ranges, range);
+    }
+    assert_eq!(ranges, vec![(0, 12), (20, 24)]);
+    // touching ranges merge too
+    bookend::add_range(&mut ranges, (12, 20));
+    assert_eq!(ranges, vec![(0, 24)]);
+}
+
+#[test]
+fn bookend() {
+    let bytenr = 1 << 20;
+    let disk = 128 * KIB;
+    let mut zstd = extent(
+        BTRFS_FILE_EXTENT_REG,
+        Compression::Zstd,
+        2 << 20,
+        32 * KIB,
+        128 * KIB,
+    );
+    zstd.num_bytes = 64 * KIB;
+    let zstd = IoctlSearchItem::extent_data(257, 32 * KIB, 53, zstd);
+    let files = vec![
+        // overlapping references to the first 32KiB
+        (
+            "a",
+            40 * KIB,
+            0,
+            vec![
+                partial(0, bytenr, disk, 0, 16 * KIB),
+                partial(16 * KIB, bytenr, disk, 8 * KIB, 24 * KIB),
+            ],
+        ),
+        (
+            "b",
+            96 * KIB,
+            0,
+            vec![partial(0, bytenr, disk, 96 * KIB, 32 * KIB), zstd],
+        ),
+        (
+            "c",
+            64 * KIB,
+            0,
+            vec![regular(0, Compression::None, 3 << 20, 64 * KIB, 64 * KIB)],
+        ),
+    ];
+    let opts = Opts {
+        bookend: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let waste = bookend::WasteStat::collect(&scan.extents.map);
+    let expected = "\
+Partially referenced extents:
+Type       Extents      Disk Usage   Wasted
+TOTAL                 2 160 KiB      80 KiB
+none                  1 128 KiB      64 KiB
+zstd                  1 32 KiB       16 KiB
+";
+    assert_eq!(trimmed(waste.display(Scale::default())), expected);
+    // every file referencing a wasteful extent is charged with all of its waste
+    let top = bookend::top_files(&scan.extents.map, scan.stats.files, 10);
+    let expected = vec![
+        (PathBuf::from("b"), 80 * KIB),
+        (PathBuf::from("a"), 64 * KIB),
+    ];
+    assert_eq!(top, expected);
+}
+
+#[test]
+fn fragmentation() {
+    let start = 1 << 20;
+    let files = vec![
+        // the compressed extent sits right after the first one on disk,
+        // and the third right after its compressed bytes
+        (
+            "a",
+            256 * KIB,
+            0,
+            vec![
+                regular(0, Compression::None, start, 64 * KIB, 64 * KIB),
+                regular(
+                    64 * KIB,
+                    Compression::Zstd,
+                    start + 64 * KIB,
+                    16 * KIB,
+                    64 * KIB,
+                ),
+                regular(
+                    128 * KIB,
+                    Compression::None,
+                    start + 80 * KIB,
+                    64 * KIB,
+                    64 * KIB,
+                ),
+                regular(192 * KIB, Compression::None, 8 << 20, 64 * KIB, 64 * KIB),
+            ],
+        ),
+        // two references to neighbouring parts of one extent
+        (
+            "b",
+            32 * KIB,
+            0,
+            vec![
+                partial(0, 16 << 20, 64 * KIB, 32 * KIB, 16 * KIB),
+                partial(16 * KIB, 16 << 20, 64 * KIB, 48 * KIB, 16 * KIB),
+            ],
+        ),
+    ];
+    let opts = Opts {
+        fragmentation: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let frag = scan.stats.frag[0];
+    assert_eq!(
+        (frag.nfile, frag.nextent, frag.nrun, frag.bytes),
+        (2, 6, 3, 288 * KIB)
+    );
+    // only files with more than one run are worth listing
+    let [(path, frag)] = &scan.stats.frag_top[..] else {
+        panic!("{:?}", scan.stats.frag_top);
+    };
+    assert_eq!(path, Path::new("a"));
+    assert_eq!((frag.nextent, frag.nrun), (4, 2));
+}
+
+#[test]
+fn size_histogram() {
+    let files = vec![(
+        "a",
+        256 * KIB,
+        0,
+        vec![
+            regular(0, Compression::None, 1 << 20, 64 * KIB, 64 * KIB),
+            regular(64 * KIB, Compression::Zstd, 2 << 20, 16 * KIB, 64 * KIB),
+            regular(128 * KIB, Compression::Zstd, 3 << 20, 20 * KIB, 128 * KIB),
+        ],
+    )];
+    let opts = Opts {
+        size_histogram: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let hist = hist::SizeHistogram::collect(&scan.extents.map);
+    let expected = "\
+none extents by size:
+     >= Size         Disk Uncompressed
+      64 KiB            1            1
+zstd extents by size:
+     >= Size         Disk Uncompressed
+      16 KiB            2            0
+      32 KiB            0            0
+      64 KiB            0            1
+     128 KiB            0            1
+";
+    assert_eq!(trimmed(hist.display(Scale::default())), expected);
+}
+
+#[test]
+fn ratio_histogram() {
+    let files = vec![(
+        "a",
+        256 * KIB,
+        0,
+        vec![
+            regular(0, Compression::None, 1 << 20, 64 * KIB, 64 * KIB),
+            regular(64 * KIB, Compression::Zstd, 2 << 20, 16 * KIB, 64 * KIB),
+            regular(128 * KIB, Compression::Zstd, 3 << 20, 20 * KIB, 128 * KIB),
+            regular(256 * KIB, Compression::Zstd, 4 << 20, 64 * KIB, 64 * KIB),
+        ],
+    )];
+    let opts = Opts {
+        ratio_histogram: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let hist = hist::RatioHistogram::collect(&scan.extents.map);
+    // uncompressed extents have no ratio to speak of
+    let expected = "\
+zstd compression ratio:
+Perc       Extents      Disk Usage   Uncompressed  Share
+10-20%                1 20 KiB       128 KiB       50.0%
+20-30%                1 16 KiB       64 KiB        25.0%
+>=100%                1 64 KiB       64 KiB        25.0%
+";
+    assert_eq!(trimmed(hist.display(Scale::default())), expected);
+}
+
+#[test]
+fn age() {
+    let aged = |pos, comp, bytenr, disk, generation| {
+        let mut item = extent(BTRFS_FILE_EXTENT_REG, comp, bytenr, disk, 64 * KIB);
+        item.generation = generation;
+        IoctlSearchItem::extent_data(257, pos, 53, item)
+    };
+    let files = vec![(
+        "a",
+        256 * KIB,
+        0,
+        vec![
+            aged(0, Compression::None, 1 << 20, 64 * KIB, 1995),
+            aged(64 * KIB, Compression::Zstd, 2 << 20, 16 * KIB, 1950),
+            aged(128 * KIB, Compression::None, 3 << 20, 64 * KIB, 1001),
+            aged(192 * KIB, Compression::None, 4 << 20, 64 * KIB, 1000),
+        ],
+    )];
+    let opts = Opts {
+        age: true,
+        ..Default::default()
+    };
+    let scan = scan(&opts, files);
+    let fsids = scan.extents.fs.fsids();
+    let generations = BTreeMap::from([(LOC.fsid, 2000)]);
+    let age = age::AgeStat::collect(&scan.extents.map, &fsids, &generations);
+    let expected = "\
+Age          Generations             Extents    Disk Usage   Uncompressed Perc   Compressed
+0-9          1995-1995                        1 64 KiB       64 KiB         100%       0.0%
+10-99        1950-1950                        1 16 KiB       64 KiB          25%     100.0%
+100-999      1001-1001                        1 64 KiB       64 KiB         100%       0.0%
+1000-9999    1000-1000                        1 64 KiB       64 KiB         100%       0.0%
+";
+    assert_eq!(trimmed(age.display(Scale::default())), expected);
+    // without the filesystem generation the newest extent is taken as current
+    let age = age::AgeStat::collect(&scan.extents.map, &fsids, &BTreeMap::new());
+    let expected = "\
+Age          Generations             Extents    Disk Usage   Uncompressed Perc   Compressed
+0-9          1995-1995                        1 64 KiB       64 KiB         100%       0.0%
+10-99        1950-1950                        1 16 KiB       64 KiB          25%     100.0%
+100-999      1000-1001                        2 128 KiB      128 KiB        100%       0.0%
+";
+    assert_eq!(trimmed(age.display(Scale::default())), expected);
+}
+
+// lay out items the way SEARCH_V2 returns them, inline data is zero filled
+fn batch(items: &[IoctlSearchItem]) -> btrfs::RawBatch {
+    let mut buf = Vec::new();
+    for item in items {
+        let raw = item.to_le_bytes();
+        let len = u32::from_le_bytes(raw[28..32].try_into().unwrap()) as usize;
+        buf.extend_from_slice(&raw[..32]);
+        buf.extend((raw[32..].iter().copied().chain(std::iter::repeat(0))).take(len));
+    }
+    btrfs::RawBatch {
+        nr_items: items.len() as u32,
+        buf,
+    }
+}
+
+#[test]
+fn record_and_replay() {
+    let a = vec![
+        regular(0, Compression::Zstd, 1 << 20, 32 * KIB, 128 * KIB),
+        hole(128 * KIB, 64 * KIB),
+        prealloc(192 * KIB, 2 << 20, 64 * KIB),
+    ];
+    let b = vec![inline(Compression::Lzo, 30, 200)];
+    let loc = Location {
+        fsid: Fsid([1; 16]),
+        subvol: btrfs::BTRFS_FS_TREE_OBJECTID,
+    };
+    let file = |path: &str, size, batches| record::FileRecord {
+        path: path.into(),
+        ino: 257,
+        size,
+        loc,
+        group: 0,
+        batches,
+    };
+    let rec = record::Recording {
+        paths: vec!["/mnt".into()],
+        subvol_paths: [(loc, "/mnt".into())].into(),
+        generations: [(loc.fsid, 100)].into(),
+        files: vec![
+            file("a", 512 * KIB, vec![batch(&a[..1]), batch(&a[1..])]),
+            file("b", 200, vec![batch(&b)]),
+        ],
+    };
+    let path = std::env::temp_dir().join(format!("compsize-rs-{}.rec", process::id()));
+    record::save(&path, &rec).unwrap();
+    let loaded = record::load(&path).unwrap();
+    assert_eq!(loaded, rec);
+    // a file under an argument that wasn't recorded
+    let mut bad = record::load(&path).unwrap();
+    bad.files[1].group = 1;
+    record::save(&path, &bad).unwrap();
+    assert!(record::load(&path).is_err());
+    // a file in a subvolume that wasn't recorded
+    bad.files[1].group = 0;
+    bad.files[1].loc.subvol += 1;
+    record::save(&path, &bad).unwrap();
+    assert!(record::load(&path).is_err());
+    fs::remove_file(&path).unwrap();
+
+    let (tx, rx) = unbounded();
+    let extents = Extents::default();
+    let quit_sig = AtomicBool::new(false);
+    let walk_info = record::replay(loaded, &quit_sig, tx);
+    assert_eq!(walk_info.generations[&loc.fsid], 100);
+    let source = source::TreeSearch::new(Since::default(), None, false);
+    let opts = Opts::default();
+    let worker = Worker::new(
+        rx,
+        &opts,
+        Since::default(),
+        Box::new(source),
+        &extents,
+        &quit_sig,
+    );
+    let mut replayed = CompsizeStat::default();
+    for stat in worker.run().fs.into_values() {
+        replayed.merge(stat);
+    }
+    let direct = scan(&opts, vec![("a", 512 * KIB, 0, a), ("b", 200, 0, b)]).total();
+    let display = |stat: &CompsizeStat| stat.display(Scale::default()).to_string();
+    assert_eq!(display(&replayed), display(&direct));
+    assert_eq!((replayed.nhole, replayed.ninline), (2, 1));
+}
+
+#[test]
+fn cache_round_trip() {
+    let key = cache::CacheKey { loc: LOC, ino: 257 };
+    let items = vec![
+        regular(0, Compression::Zstd, 1 << 20, 32 * KIB, 128 * KIB),
+        hole(128 * KIB, 64 * KIB),
+    ];
+    let mut cache = cache::Cache::default();
+    cache.files.insert(key, items.clone());
+    // the oldest generation a worker read the subvolume at wins
+    let mut other = cache::Cache::default();
+    other.generations.insert(LOC, 42);
+    cache.generations.insert(LOC, 50);
+    cache.merge(other);
+    let path = std::env::temp_dir().join(format!("compsize-rs-{}.cache", process::id()));
+    cache::save(&path, &cache).unwrap();
+    let loaded = cache::load(&path).unwrap();
+    assert_eq!(loaded.generations[&LOC], 42);
+    let raw = |items: &[IoctlSearchItem]| items.iter().map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+    assert_eq!(raw(&loaded.files[&key]), raw(&items));
+    // files of a subvolume without a generation can't be checked
+    cache.generations.clear();
+    cache::save(&path, &cache).unwrap();
+    assert!(cache::load(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn malformed_items() {
+    let reg = |f: fn(&mut FileExtentItem)| {
+        let mut item = extent(
+            BTRFS_FILE_EXTENT_REG,
+            Compression::None,
+            1 << 20,
+            4096,
+            4096,
+        );
+        f(&mut item);
+        IoctlSearchItem::extent_data(257, 0, 53, item)
+    };
+    let bad_comp = reg(|item| item.compression = 4);
+    let bad_type = reg(|item| item.r#type = 3);
+    let past_end = reg(|item| item.offset = u64::MAX);
+    let short_inline = IoctlSearchItem::extent_data(
+        257,
+        0,
+        20,
+        extent(BTRFS_FILE_EXTENT_INLINE, Compression::None, 0, 0, 100),
+    );
+    // an inode item where an extent was expected
+    let mut raw = reg(|_| {}).to_le_bytes();
+    raw[24] = 1;
+    let not_extent = IoctlSearchItem::from_le_bytes(&raw);
+    for item in [bad_comp, bad_type, past_end, short_inline, not_extent] {
+        assert!(item.parse().is_err(), "{:?}", item);
+    }
+    assert_eq!(
+        inline(Compression::None, 0, 100).parse().unwrap().stat.disk,
+        0
+    );
+}
+
+fn valid_batch() -> btrfs::RawBatch {
+    batch(&[
+        regular(0, Compression::Zstd, 1 << 20, 4096, 128 * KIB),
+        inline(Compression::None, 30, 30),
+        prealloc(128 * KIB, 2 << 20, 8192),
+        hole(136 * KIB, 4096),
+    ])
+}
+
+#[test]
+fn unaligned_items() {
+    // every item past offset 0 sits at an odd address
+    let items: Vec<_> = valid_batch().items().collect();
+    assert_eq!(items.len(), 4);
+    for item in items {
+        item.parse().unwrap();
+    }
+}
+
+// a valid batch cut short, with a few bytes changed
+fn damaged_batch() -> impl Strategy<Value = Vec<u8>> {
+    let buf = valid_batch().buf;
+    let len = buf.len();
+    let changes = prop::collection::vec((0..len, any::<u8>()), 0..8);
+    (0..=len, changes).prop_map(move |(end, changes)| {
+        let mut buf = buf.clone();
+        for (at, b) in changes {
+            buf[at] = b;
+        }
+        buf.truncate(end);
+        buf
+    })
+}
+
+fn extent_type() -> impl Strategy<Value = u8> {
+    prop_oneof![
+        Just(BTRFS_FILE_EXTENT_INLINE),
+        Just(BTRFS_FILE_EXTENT_REG),
+        Just(BTRFS_FILE_EXTENT_PREALLOC),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn items_never_panic(
+        nr_items: u32,
+        buf in prop_oneof![prop::collection::vec(any::<u8>(), 0..1024), damaged_batch()],
+    ) {
+        let raw = btrfs::RawBatch { nr_items, buf };
+        for item in raw.items() {
+            let _ = item.parse();
+        }
+    }
+
+    #[test]
+    fn classification_round_trips(
+        r#type in extent_type(),
+        comp in 0..4u8,
+        pos in 0..1u64 << 40,
+        bytenr in 0..1u64 << 40,
+        disk in 1..1u64 << 20,
+        ram in 1..1u64 << 20,
+        data in 0..4096u32,
+    ) {
+        let comp = Compression::from_usize(comp as usize);
+        let mut item = extent(r#type, comp, bytenr << 12, disk, ram);
+        let len = if r#type == BTRFS_FILE_EXTENT_INLINE { 21 + data } else { 53 };
+        if r#type == BTRFS_FILE_EXTENT_INLINE {
+            item.disk_bytenr = 0;
+            item.disk_num_bytes = 0;
+            item.num_bytes = 0;
+        }
+        let item = IoctlSearchItem::extent_data(257, pos, len, item);
+        let raw = batch(&[item]);
+        let decoded: Vec<_> = raw.items().collect();
+        prop_assert_eq!(decoded.len(), 1);
+        let ext = decoded[0].parse().unwrap();
+        prop_assert_eq!(ext, item.parse().unwrap());
+        let expected = match r#type {
+            BTRFS_FILE_EXTENT_INLINE => ExtentType::Inline,
+            _ if bytenr == 0 => ExtentType::Hole,
+            BTRFS_FILE_EXTENT_REG => ExtentType::Regular,
+            _ => ExtentType::Prealloc,
+        };
+        prop_assert_eq!(ext.key.r#type(), expected);
+        prop_assert_eq!(ext.comp, comp);
+        prop_assert_eq!(ext.pos, pos);
+    }
+}
+
+// (objectid, type, offset) and data
+type LeafItem = ((u64, u32, u64), Vec<u8>);
+
+// a tree leaf of 4K nodes
+fn leaf(bytenr: u64, items: &[LeafItem]) -> Vec<u8> {
+    let mut block = vec![0; 4096];
+    block[0x20..0x30].copy_from_slice(&[7; 16]);
+    block[0x30..0x38].copy_from_slice(&bytenr.to_le_bytes());
+    block[0x50..0x58].copy_from_slice(&10u64.to_le_bytes());
+    block[0x60..0x64].copy_from_slice(&(items.len() as u32).to_le_bytes());
+    let mut end = block.len();
+    for (i, ((objectid, r#type, offset), data)) in items.iter().enumerate() {
+        end -= data.len();
+        block[end..end + data.len()].copy_from_slice(data);
+        let at = 101 + i * 25;
+        block[at..at + 8].copy_from_slice(&objectid.to_le_bytes());
+        block[at + 8] = *r#type as u8;
+        block[at + 9..at + 17].copy_from_slice(&offset.to_le_bytes());
+        block[at + 17..at + 21].copy_from_slice(&((end - 101) as u32).to_le_bytes());
+        block[at + 21..at + 25].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    }
+    block
+}
+
+// one stripe on device 1
+fn chunk_item(len: u64, physical: u64) -> Vec<u8> {
+    let mut ret = vec![0; 80];
+    ret[..8].copy_from_slice(&len.to_le_bytes());
+    ret[44] = 1;
+    ret[48] = 1;
+    ret[56..64].copy_from_slice(&physical.to_le_bytes());
+    ret
+}
+
+fn inode_item(size: u64, mode: u32) -> Vec<u8> {
+    let mut ret = vec![0; 160];
+    ret[16..24].copy_from_slice(&size.to_le_bytes());
+    ret[40] = 1;
+    ret[52..56].copy_from_slice(&mode.to_le_bytes());
+    ret
+}
+
+fn name_ref(head: &[u8], name: &str) -> Vec<u8> {
+    let mut ret = head.to_vec();
+    ret.extend((name.len() as u16).to_le_bytes());
+    ret.extend(name.as_bytes());
+    ret
+}
+
+fn root_item(bytenr: u64, refs: u32) -> Vec<u8> {
+    let mut ret = vec![0; 439];
+    ret[176..184].copy_from_slice(&bytenr.to_le_bytes());
+    ret[216..220].copy_from_slice(&refs.to_le_bytes());
+    ret
+}
+
+// the item without its header, inline data zero filled
+fn extent_bytes(item: IoctlSearchItem) -> Vec<u8> {
+    batch(&[item]).buf[32..].to_vec()
+}
+
+#[test]
+fn root_tree() {
+    use btrfs::{BTRFS_ROOT_ITEM_KEY as ROOT, BTRFS_ROOT_REF_KEY as ROOT_REF};
+    let root_ref = |dir: u64, name| name_ref(&[dir.to_le_bytes(), [0; 8]].concat(), name);
+    let items = [
+        // the extent tree isn't a subvolume
+        ((2, ROOT, 0), root_item(0x1000, 1)),
+        ((5, ROOT, 0), root_item(0x2000, 1)),
+        ((5, ROOT_REF, 256), root_ref(300, "a")),
+        ((256, ROOT, 0), root_item(0x3000, 1)),
+        ((256, ROOT_REF, 257), root_ref(256, "b")),
+        ((257, ROOT, 0), root_item(0x4000, 1)),
+        // below a deleted subvolume
+        ((258, ROOT, 0), root_item(0x5000, 1)),
+        ((259, ROOT, 0), root_item(0x6000, 0)),
+        ((259, ROOT_REF, 258), root_ref(256, "c")),
+        // in a directory that can't be looked up
+        ((5, ROOT_REF, 260), root_ref(301, "d")),
+        ((260, ROOT, 0), root_item(0x7000, 1)),
+    ];
+    let mut tree = tree::RootTree::default();
+    for ((objectid, r#type, offset), data) in &items {
+        tree.add(btrfs::TreeItem::new(*objectid, *r#type, *offset, 1, data));
+    }
+    // what INO_LOOKUP would find, directory 256 is the root of its subvolume
+    let dirs = HashMap::from([((5, 300), "dir"), ((256, 256), "")]);
+    let dir_path = |parent, dir| Ok(dirs.get(&(parent, dir)).map(PathBuf::from));
+    // --all-subvolumes
+    let paths: Vec<_> = tree
+        .subvols
+        .keys()
+        .map(|&id| tree.path(id, dir_path).unwrap())
+        .collect();
+    let expected = [
+        "/",
+        "/dir/a",
+        "/dir/a/b",
+        "<subvolume 258>",
+        "<subvolume 260>",
+    ];
+    assert_eq!(paths, expected.map(PathBuf::from));
+    // --subvol-id
+    tree.select(257).unwrap();
+    let ids: Vec<_> = tree.subvols.keys().copied().collect();
+    assert_eq!(ids, [257]);
+    assert_eq!(tree.path(257, dir_path).unwrap(), Path::new("/dir/a/b"));
+    assert!(tree.select(259).is_err());
+}
+
+#[test]
+fn image() {
+    use btrfs::{
+        BTRFS_CHUNK_ITEM_KEY as CHUNK, BTRFS_EXTENT_DATA_KEY as DATA,
+        BTRFS_INODE_ITEM_KEY as INODE, BTRFS_INODE_REF_KEY as REF, BTRFS_ROOT_ITEM_KEY as ROOT,
+        BTRFS_ROOT_REF_KEY as ROOT_REF,
+    };
+    const DIR: u32 = 0o040755;
+    const FILE: u32 = 0o100644;
+    let top = |ino: u64| ((ino, INODE, 0), inode_item(0, DIR));
+    let up = |ino: u64| ((ino, REF, ino), name_ref(&[0; 8], ".."));
+    let zstd = regular(0, Compression::Zstd, 64 << 20, 8 * KIB, 128 * KIB);
+    let (sys, meta) = (0x100000, 0x10000000);
+    let blocks = [
+        // chunk tree, the metadata chunk lies elsewhere on the device
+        leaf(
+            sys,
+            &[
+                ((256, CHUNK, sys), chunk_item(0x100000, sys)),
+                ((256, CHUNK, meta), chunk_item(0x100000, 0x200000)),
+            ],
+        ),
+        // root tree, subvolume 257 is linked in directory 257, and 256 was moved into it
+        leaf(
+            meta,
+            &[
+                ((5, ROOT, 0), root_item(meta + 4096, 1)),
+                (
+                    (5, ROOT_REF, 257),
+                    name_ref(&[257u64.to_le_bytes(), [0; 8]].concat(), "new"),
+                ),
+                ((256, ROOT, 0), root_item(meta + 8192, 1)),
+                ((257, ROOT, 0), root_item(meta + 12288, 1)),
+                (
+                    (257, ROOT_REF, 256),
+                    name_ref(&[256u64.to_le_bytes(), [0; 8]].concat(), "sub"),
+                ),
+            ],
+        ),
+        // /d/a, /b
+        leaf(
+            meta + 4096,
+            &[
+                top(256),
+                up(256),
+                ((257, INODE, 0), inode_item(0, DIR)),
+                ((257, REF, 256), name_ref(&[0; 8], "d")),
+                ((258, INODE, 0), inode_item(128 * KIB, FILE)),
+                ((258, REF, 257), name_ref(&[0; 8], "a")),
+                ((258, DATA, 0), extent_bytes(zstd)),
+                ((259, INODE, 0), inode_item(100, FILE)),
+                ((259, REF, 256), name_ref(&[0; 8], "b")),
+                (
+                    (259, DATA, 0),
+                    extent_bytes(inline(Compression::None, 100, 100)),
+                ),
+            ],
+        ),
+        // /d/new/sub/c shares the extent of /d/a
+        leaf(
+            meta + 8192,
+            &[
+                top(256),
+                up(256),
+                ((257, INODE, 0), inode_item(128 * KIB, FILE)),
+                ((257, REF, 256), name_ref(&[0; 8], "c")),
+                ((257, DATA, 0), extent_bytes(zstd)),
+            ],
+        ),
+        // /d/new is empty
+        leaf(meta + 12288, &[top(256), up(256)]),
+    ];
+    let mut sb = vec![0; 4096];
+    sb[0x20..0x30].copy_from_slice(&[7; 16]);
+    sb[0x40..0x48].copy_from_slice(b"_BHRfS_M");
+    sb[0x48..0x50].copy_from_slice(&10u64.to_le_bytes());
+    sb[0x50..0x58].copy_from_slice(&meta.to_le_bytes());
+    sb[0x58..0x60].copy_from_slice(&sys.to_le_bytes());
+    sb[0x94..0x98].copy_from_slice(&4096u32.to_le_bytes());
+    sb[0xc9] = 1;
+    let mut sys_chunk = vec![0; 17];
+    sys_chunk[..8].copy_from_slice(&256u64.to_le_bytes());
+    sys_chunk[8] = CHUNK as u8;
+    sys_chunk[9..17].copy_from_slice(&sys.to_le_bytes());
+    sys_chunk.extend(chunk_item(0x100000, sys));
+    sb[0xa0..0xa4].copy_from_slice(&(sys_chunk.len() as u32).to_le_bytes());
+    sb[0x32b..0x32b + sys_chunk.len()].copy_from_slice(&sys_chunk);
+
+    let mut disk = vec![0; 0x200000 + 4 * 4096];
+    disk[0x10000..0x11000].copy_from_slice(&sb);
+    disk[0x100000..0x101000].copy_from_slice(&blocks[0]);
+    for (i, block) in blocks[1..].iter().enumerate() {
+        disk[0x200000 + i * 4096..0x201000 + i * 4096].copy_from_slice(block);
+    }
+    let path = std::env::temp_dir().join(format!("compsize-rs-{}.img", process::id()));
+    fs::write(&path, disk).unwrap();
+    let (tx, rx) = unbounded();
+    let quit_sig = AtomicBool::new(false);
+    let info = image::walk(&path, &quit_sig, tx);
+    fs::remove_file(&path).unwrap();
+    assert!(!quit_sig.load(Ordering::Acquire));
+    let subvols: Vec<_> = info
+        .subvol_paths
+        .iter()
+        .map(|(l, p)| (l.subvol, p.clone()))
+        .collect();
+    assert_eq!(
+        subvols,
+        [
+            (5, "/".into()),
+            (256, "/d/new/sub".into()),
+            (257, "/d/new".into())
+        ]
+    );
+
+    let jobs: Vec<_> = rx.iter().collect();
+    let paths: Vec<_> = jobs.iter().map(|j| j.path.to_str().unwrap()).collect();
+    assert_eq!(paths, ["/d/a", "/b", "/d/new/sub/c"]);
+    let files = jobs
+        .iter()
+        .map(|j| {
+            (
+                j.path.to_str().unwrap(),
+                j.size,
+                0,
+                j.items.clone().unwrap(),
+            )
+        })
+        .collect();
+    let total = scan(&Opts::default(), files).total();
+    assert_eq!(
+        (total.nfile, total.ninline, total.nextent, total.nref),
+        (3, 1, 1, 2)
+    );
+    assert_eq!(total.stat[Compression::Zstd as usize].disk, 8 * KIB);
+}
+
+// one send stream command, in v2 the data comes last and without a length
+fn send_cmd(version: u32, cmd: u16, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (r#type, value) in attrs {
+        body.extend(r#type.to_le_bytes());
+        if !(*r#type == 19 && version >= 2) {
+            body.extend((value.len() as u16).to_le_bytes());
+        }
+        body.extend(*value);
+    }
+    let mut ret = Vec::new();
+    ret.extend((body.len() as u32).to_le_bytes());
+    ret.extend(cmd.to_le_bytes());
+    ret.extend([0; 4]);
+    ret.extend(body);
+    let crc = send::crc32c(0, &ret);
+    ret[6..10].copy_from_slice(&crc.to_le_bytes());
+    ret
+}
+
+#[test]
+fn crc32c() {
+    assert_eq!(!send::crc32c(!0, b"123456789"), 0xe306_9283);
+}
+
+// the files receiving `stream` would create, which must go through without errors
+fn receive(stream: &[u8], name: &str) -> (WalkInfo, Vec<FileJob>) {
+    let file = std::env::temp_dir().join(format!("compsize-rs-{}-{}.send", process::id(), name));
+    fs::write(&file, stream).unwrap();
+    let (tx, rx) = unbounded();
+    let quit_sig = AtomicBool::new(false);
+    let info = send::walk(&file, &quit_sig, tx);
+    fs::remove_file(&file).unwrap();
+    assert!(!quit_sig.load(Ordering::Acquire));
+    (info, rx.iter().collect())
+}
+
+// the jobs carry their items
+fn scan_jobs(jobs: Vec<FileJob>) -> Scan {
+    let source = source::TreeSearch::new(Since::default(), None, false);
+    run(&Opts::default(), Box::new(source), jobs)
+}
+
+#[test]
+fn send_stream() {
+    let (uuid, other) = ([1; 16], [2; 16]);
+    let path = |p: &'static str| (15, p.as_bytes());
+    let num = |attr: u16, n: u64| (attr, n.to_le_bytes().to_vec());
+    let mut stream = b"btrfs-stream\0".to_vec();
+    stream.extend(2u32.to_le_bytes());
+    let v2 = |cmd, attrs: &[(u16, &[u8])]| send_cmd(2, cmd, attrs);
+    let data = vec![0; 32 * 1024];
+    let (at, size) = (num(18, 0), num(4, 40000));
+    let (half, zstd) = (num(18, 32 * 1024), num(30, 2));
+    let (file_len, len, offset) = (num(27, 128 * KIB), num(28, 128 * KIB), num(29, 0));
+    let (clone_len, clone_at) = (num(24, 64 * KIB), num(23, 64 * KIB));
+    let (mode, prealloc) = (num(25, 0), num(4, 8192));
+    let cmds = [
+        v2(1, &[path("snap"), (1, &uuid)]),
+        // two writes that make one extent, cut short afterwards
+        v2(3, &[path("o257-5-0")]),
+        v2(9, &[path("o257-5-0"), (16, b"a")]),
+        v2(15, &[path("a"), (at.0, &at.1), (19, &data)]),
+        v2(15, &[path("a"), (half.0, &half.1), (19, &data)]),
+        v2(17, &[path("a"), (size.0, &size.1)]),
+        // compressed as sent
+        v2(3, &[path("b")]),
+        v2(
+            25,
+            &[
+                path("b"),
+                (at.0, &at.1),
+                (file_len.0, &file_len.1),
+                (len.0, &len.1),
+                (offset.0, &offset.1),
+                (zstd.0, &zstd.1),
+                (19, &[7; 10000]),
+            ],
+        ),
+        // the second half of b
+        v2(3, &[path("c")]),
+        v2(
+            16,
+            &[
+                path("c"),
+                (at.0, &at.1),
+                (clone_len.0, &clone_len.1),
+                (20, &uuid),
+                (22, b"b"),
+                (clone_at.0, &clone_at.1),
+            ],
+        ),
+        v2(3, &[path("d")]),
+        v2(15, &[path("d"), (at.0, &at.1), (19, &[1; 100])]),
+        v2(3, &[path("e")]),
+        v2(11, &[path("e")]),
+        v2(3, &[path("dir/f")]),
+        v2(
+            23,
+            &[
+                path("dir/f"),
+                (mode.0, &mode.1),
+                (at.0, &at.1),
+                (prealloc.0, &prealloc.1),
+            ],
+        ),
+        v2(9, &[path("dir"), (16, b"dir2")]),
+        v2(21, &[]),
+    ];
+    stream.extend(cmds.concat());
+    // a v1 stream of another subvolume follows
+    stream.extend(b"btrfs-stream\0");
+    stream.extend(1u32.to_le_bytes());
+    for cmd in [
+        send_cmd(1, 1, &[path("other"), (1, &other)]),
+        send_cmd(1, 3, &[path("g")]),
+        send_cmd(1, 15, &[path("g"), (at.0, &at.1), (19, &data)]),
+        send_cmd(1, 21, &[]),
+    ] {
+        stream.extend(cmd);
+    }
+
+    let (info, jobs) = receive(&stream, "stream");
+    let subvols: Vec<_> = info.subvol_paths.values().collect();
+    assert_eq!(subvols, ["snap", "other"]);
+    let paths: Vec<_> = jobs.iter().map(|j| j.path.to_str().unwrap()).collect();
+    assert_eq!(
+        paths,
+        [
+            "snap/a",
+            "snap/b",
+            "snap/c",
+            "snap/d",
+            "snap/dir2/f",
+            "other/g"
+        ]
+    );
+    let total = scan_jobs(jobs).total();
+    assert_eq!((total.nfile, total.ninline), (6, 1));
+    // a, b shared with c, g and the preallocated f
+    assert_eq!((total.nextent, total.nref), (4, 5));
+    let zstd = total.stat[Compression::Zstd as usize];
+    assert_eq!(
+        (zstd.disk, zstd.uncomp, zstd.refd),
+        (12 * KIB, 128 * KIB, 192 * KIB)
+    );
+    let none = total.stat[Compression::None as usize];
+    assert_eq!(
+        (none.disk, none.uncomp, none.refd),
+        // with d inline
+        (96 * KIB + 100, 96 * KIB + 100, 72 * KIB + 100)
+    );
+    assert_eq!(total.prealloc.disk, 8 * KIB);
+}
+
+#[test]
+fn incremental_send_stream() {
+    let path = |p: &'static str| (15, p.as_bytes());
+    let at = |pos: u64| (18, pos.to_le_bytes());
+    let (start, middle) = (at(0), at(64 * KIB));
+    let size = (4, 100u64.to_le_bytes());
+    let stream = [
+        b"btrfs-stream\0".to_vec(),
+        2u32.to_le_bytes().to_vec(),
+        send_cmd(2, 2, &[path("snap2"), (1, &[3; 16])]),
+        // files of the parent, only the changes are sent
+        send_cmd(
+            2,
+            15,
+            &[path("big"), (middle.0, &middle.1), (19, &[1; 8192])],
+        ),
+        send_cmd(2, 17, &[path("t"), (size.0, &size.1)]),
+        send_cmd(2, 9, &[path("x"), (16, b"y")]),
+        send_cmd(2, 15, &[path("y"), (start.0, &start.1), (19, &[2; 100])]),
+        // and one of its own
+        send_cmd(2, 3, &[path("new")]),
+        send_cmd(2, 15, &[path("new"), (start.0, &start.1), (19, &[3; 100])]),
+        send_cmd(2, 21, &[]),
+    ]
+    .concat();
+    let (_, jobs) = receive(&stream, "incremental");
+    let paths: Vec<_> = jobs.iter().map(|j| j.path.to_str().unwrap()).collect();
+    assert_eq!(paths, ["snap2/big", "snap2/t", "snap2/y", "snap2/new"]);
+    let partial: Vec<_> = jobs.iter().map(|j| j.partial).collect();
+    assert_eq!(partial, [true, true, true, false]);
+    let total = scan_jobs(jobs).total();
+    // what the parent holds around the changes isn't a hole, and y may be bigger than
+    // what was written to it, so it isn't inlined
+    assert_eq!((total.nfile, total.ninline, total.nhole), (4, 1, 0));
+    assert_eq!((total.nextent, total.nref), (2, 2));
+    let none = total.stat[Compression::None as usize];
+    assert_eq!((none.disk, none.refd), (12 * KIB + 100, 12 * KIB + 100));
+}
+
+#[test]
+fn unaligned_send_writes() {
+    let path = |p: &'static str| (15, p.as_bytes());
+    let at = |pos: u64| (18, pos.to_le_bytes());
+    let (first, second) = (at(0), at(6000));
+    let stream = [
+        b"btrfs-stream\0".to_vec(),
+        2u32.to_le_bytes().to_vec(),
+        send_cmd(2, 1, &[path("snap"), (1, &[1; 16])]),
+        send_cmd(2, 3, &[path("a")]),
+        // both write to the second sector
+        send_cmd(2, 15, &[path("a"), (first.0, &first.1), (19, &[1; 6000])]),
+        send_cmd(2, 15, &[path("a"), (second.0, &second.1), (19, &[2; 4000])]),
+        send_cmd(2, 21, &[]),
+    ]
+    .concat();
+    let (_, jobs) = receive(&stream, "unaligned");
+    assert_eq!(jobs[0].size, 10000);
+    let total = scan_jobs(jobs).total();
+    assert_eq!((total.nextent, total.nref), (1, 1));
+    let none = total.stat[Compression::None as usize];
+    assert_eq!(
+        (none.disk, none.uncomp, none.refd),
+        (12 * KIB, 12 * KIB, 12 * KIB)
+    );
+}
+
+#[test]
+fn bad_send_streams() {
+    let path = |p: &'static str| (15, p.as_bytes());
+    let at = (18, (u64::MAX - 10).to_le_bytes().to_vec());
+    let header = |version: u32| [&b"btrfs-stream\0"[..], &version.to_le_bytes()].concat();
+    let past_end = [
+        header(2),
+        send_cmd(2, 1, &[path("snap"), (1, &[1; 16])]),
+        send_cmd(2, 3, &[path("a")]),
+        send_cmd(2, 15, &[path("a"), (at.0, &at.1), (19, &[0; 100])]),
+    ]
+    .concat();
+    // only the length of the command is there
+    let mut too_long = header(1);
+    too_long.extend(u32::MAX.to_le_bytes());
+    too_long.extend([0; 6]);
+    // data on disk for an extent of nothing
+    let zero = (28, 0u64.to_le_bytes().to_vec());
+    let empty_extent = [
+        header(2),
+        send_cmd(2, 1, &[path("snap"), (1, &[1; 16])]),
+        send_cmd(2, 3, &[path("a")]),
+        send_cmd(
+            2,
+            25,
+            &[
+                path("a"),
+                (18, &zero.1),
+                (27, &zero.1),
+                (zero.0, &zero.1),
+                (29, &zero.1),
+                (30, &2u64.to_le_bytes()),
+                (19, &[7; 100]),
+            ],
+        ),
+        send_cmd(2, 21, &[]),
+    ]
+    .concat();
+    for (i, stream) in [past_end, too_long, empty_extent].iter().enumerate() {
+        let file = std::env::temp_dir().join(format!("compsize-rs-{}-{}.bad", process::id(), i));
+        fs::write(&file, stream).unwrap();
+        let (tx, rx) = unbounded();
+        let quit_sig = AtomicBool::new(false);
+        send::walk(&file, &quit_sig, tx);
+        fs::remove_file(&file).unwrap();
+        assert!(quit_sig.load(Ordering::Acquire));
+        assert_eq!(rx.iter().count(), 0);
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    os::{linux::fs::MetadataExt as _, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    btrfs::{
+        self, IoctlSearchItem, RootItem, Sv2Args, TreeItem, BTRFS_EXTENT_DATA_KEY,
+        BTRFS_FIRST_FREE_OBJECTID, BTRFS_FS_TREE_OBJECTID, BTRFS_INODE_ITEM_KEY,
+        BTRFS_INODE_REF_KEY, BTRFS_LAST_FREE_OBJECTID, BTRFS_ROOT_ITEM_KEY, BTRFS_ROOT_REF_KEY,
+        BTRFS_ROOT_TREE_OBJECTID,
+    },
+    FileJob, Location, WalkInfo, WorkerTx,
+};
+
+// the regular file whose items are being collected
+struct Inode {
+    ino: u64,
+    size: u64,
+    nlink: u32,
+    // parent directory and name of the first link
+    link: Option<(u64, Vec<u8>)>,
+    items: Vec<IoctlSearchItem>,
+}
+
+// finds the files of one subvolume and joins their paths
+pub(crate) struct Subvolume {
+    // looks up directories, without it they must all be in `dirs` already
+    fd: Option<Arc<File>>,
+    // what `fd` looks into, 0 for its own subvolume
+    pub(crate) tree_id: u64,
+    root: PathBuf,
+    loc: Location,
+    group: u32,
+    pub(crate) dirs: HashMap<u64, PathBuf>,
+}
+impl Subvolume {
+    pub(crate) fn new(fd: Option<Arc<File>>, root: PathBuf, loc: Location, group: u32) -> Self {
+        Self {
+            fd,
+            tree_id: 0,
+            root,
+            loc,
+            group,
+            dirs: HashMap::new(),
+        }
+    }
+
+    fn path(&mut self, inode: &Inode) -> io::Result<PathBuf> {
+        let Some((dir, name)) = &inode.link else {
+            return Ok(self.root.join(format!("<inode {}>", inode.ino)));
+        };
+        if !self.dirs.contains_key(dir) {
+            let path = match &self.fd {
+                Some(fd) => self.root.join(btrfs::dir_path(fd, self.tree_id, *dir)?),
+                None => self.root.join(format!("<inode {}>", dir)),
+            };
+            self.dirs.insert(*dir, path);
+        }
+        Ok(self.dirs[dir].join(OsStr::from_bytes(name)))
+    }
+
+    fn send(&mut self, inode: Inode, tx: &WorkerTx) -> io::Result<()> {
+        // unlinked but still open, not reachable by a walk either
+        if inode.nlink == 0 {
+            return Ok(());
+        }
+        let job = FileJob {
+            path: self.path(&inode)?,
+            ino: inode.ino,
+            size: inode.size,
+            loc: self.loc,
+            fd: self.fd.clone(),
+            group: self.group,
+            items: Some(inode.items),
+            partial: false,
+        };
+        tx.send(job).unwrap();
+        Ok(())
+    }
+
+    // `search` hands over every item of the tree in key order until told to stop
+    pub(crate) fn send_files(
+        &mut self,
+        quit_sig: &AtomicBool,
+        tx: &WorkerTx,
+        search: impl FnOnce(&mut dyn FnMut(TreeItem) -> bool) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut inode: Option<Inode> = None;
+        let mut ret = Ok(());
+        // the items of an inode are sorted by type, the inode item comes first
+        search(&mut |item| {
+            if quit_sig.load(Ordering::Acquire) {
+                return false;
+            }
+            if inode.as_ref().is_some_and(|i| i.ino != item.objectid()) {
+                ret = self.send(inode.take().unwrap(), tx);
+                if ret.is_err() {
+                    return false;
+                }
+            }
+            match item.r#type() {
+                BTRFS_INODE_ITEM_KEY => {
+                    inode = item.inode().filter(|i| i.is_file()).map(|i| Inode {
+                        ino: item.objectid(),
+                        size: i.size,
+                        nlink: i.nlink,
+                        link: None,
+                        items: Vec::new(),
+                    });
+                }
+                BTRFS_INODE_REF_KEY => {
+                    if let (Some(inode), Some((dir, name))) = (&mut inode, item.inode_ref()) {
+                        inode.link.get_or_insert_with(|| (dir, name.to_owned()));
+                    }
+                }
+                BTRFS_EXTENT_DATA_KEY => {
+                    if let Some(inode) = &mut inode {
+                        inode.items.push(item.extent_item());
+                    }
+                }
+                _ => {}
+            }
+            true
+        })?;
+        ret?;
+        match inode.take() {
+            Some(inode) if !quit_sig.load(Ordering::Acquire) => self.send(inode, tx),
+            _ => Ok(()),
+        }
+    }
+}
+
+// nearest ancestor that is the root of the same subvolume
+fn subvolume_root(path: &Path) -> PathBuf {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let dev = fs::metadata(&path).map(|m| m.st_dev()).ok();
+    for dir in path.ancestors() {
+        match fs::metadata(dir) {
+            Ok(meta) if Some(meta.st_dev()) != dev => break,
+            Ok(meta) if meta.is_dir() && meta.st_ino() == BTRFS_FIRST_FREE_OBJECTID => {
+                return dir.to_owned()
+            }
+            _ => {}
+        }
+    }
+    path
+}
+
+// the subvolumes searched so far, per path if the reports tell the paths apart
+struct Searched {
+    per_group: bool,
+    subvols: HashSet<(Location, u32)>,
+}
+impl Searched {
+    // returns true if `loc` wasn't searched for `group` yet
+    fn insert(&mut self, loc: Location, group: u32) -> bool {
+        let group = if self.per_group { group } else { 0 };
+        self.subvols.insert((loc, group))
+    }
+}
+
+// search the whole tree of the subvolume every path lives in, nothing is walked or opened;
+// with `per_group` a subvolume holding several paths is searched for every one of them
+pub(crate) fn walk(
+    paths: Vec<String>,
+    per_group: bool,
+    quit_sig: &AtomicBool,
+    tx: WorkerTx,
+) -> WalkInfo {
+    let mut info = WalkInfo::default();
+    let mut sv2_arg = Sv2Args::new();
+    let mut searched = Searched {
+        per_group,
+        subvols: HashSet::new(),
+    };
+    for (group, arg) in paths.into_iter().enumerate() {
+        if quit_sig.load(Ordering::Acquire) {
+            break;
+        }
+        let group = group as u32;
+        if let Err(e) = walk_subvolume(
+            &arg,
+            group,
+            &mut sv2_arg,
+            &mut info,
+            &mut searched,
+            quit_sig,
+            &tx,
+        ) {
+            quit_sig.store(true, Ordering::Release);
+            if e.raw_os_error() == Some(25) {
+                eprintln!("{}: Not btrfs (or tree search unsupported)", arg);
+            } else {
+                eprintln!("{}: SEARCH_V2: {}", arg, e);
+            }
+        }
+    }
+    info
+}
+
+fn walk_subvolume(
+    arg: &str,
+    group: u32,
+    sv2_arg: &mut Sv2Args,
+    info: &mut WalkInfo,
+    searched: &mut Searched,
+    quit_sig: &AtomicBool,
+    tx: &WorkerTx,
+) -> io::Result<()> {
+    let fd = Arc::new(File::open(arg)?);
+    let fs_info = btrfs::fs_info(&fd)?;
+    let loc = Location {
+        fsid: fs_info.fsid,
+        subvol: btrfs::subvol_id(&fd)?,
+    };
+    // every file once, or once per path when the paths are told apart
+    if !searched.insert(loc, group) {
+        return Ok(());
+    }
+    let root = subvolume_root(arg.as_ref());
+    info.subvol_paths.entry(loc).or_insert_with(|| root.clone());
+    info.generations.insert(fs_info.fsid, fs_info.generation);
+    let mut subvol = Subvolume::new(Some(fd.clone()), root, loc, group);
+    subvol.send_files(quit_sig, tx, |f| {
+        Ok(sv2_arg.search_tree(&fd, 0, BTRFS_INODE_ITEM_KEY, BTRFS_EXTENT_DATA_KEY, f)?)
+    })
+}
+
+// search the trees of subvolume `id`, or of all of them, on the filesystem of every path;
+// none of them needs to be mounted
+pub(crate) fn walk_ids(
+    paths: Vec<String>,
+    id: Option<u64>,
+    quit_sig: &AtomicBool,
+    tx: WorkerTx,
+) -> WalkInfo {
+    let mut info = WalkInfo::default();
+    let mut sv2_arg = Sv2Args::new();
+    for (group, arg) in paths.into_iter().enumerate() {
+        if quit_sig.load(Ordering::Acquire) {
+            break;
+        }
+        if let Err(e) = walk_filesystem(
+            &arg,
+            id,
+            group as u32,
+            &mut sv2_arg,
+            &mut info,
+            quit_sig,
+            &tx,
+        ) {
+            quit_sig.store(true, Ordering::Release);
+            match e.raw_os_error() {
+                Some(25) => eprintln!("{}: Not btrfs (or tree search unsupported)", arg),
+                Some(_) => eprintln!("{}: SEARCH_V2: {}", arg, e),
+                None => eprintln!("{}: {}", arg, e),
+            }
+        }
+    }
+    info
+}
+
+fn walk_filesystem(
+    arg: &str,
+    id: Option<u64>,
+    group: u32,
+    sv2_arg: &mut Sv2Args,
+    info: &mut WalkInfo,
+    quit_sig: &AtomicBool,
+    tx: &WorkerTx,
+) -> io::Result<()> {
+    let fd = Arc::new(File::open(arg)?);
+    let fs_info = btrfs::fs_info(&fd)?;
+    // every subvolume once, even if several paths are on the filesystem
+    if info.generations.contains_key(&fs_info.fsid) {
+        return Ok(());
+    }
+    info.generations.insert(fs_info.fsid, fs_info.generation);
+    let mut root_tree = RootTree::default();
+    let (min_type, max_type) = (BTRFS_ROOT_ITEM_KEY, BTRFS_ROOT_REF_KEY);
+    sv2_arg.search_tree(&fd, BTRFS_ROOT_TREE_OBJECTID, min_type, max_type, |item| {
+        root_tree.add(item);
+        true
+    })?;
+    if let Some(id) = id {
+        root_tree.select(id)?;
+    }
+    for &id in root_tree.subvols.keys() {
+        if quit_sig.load(Ordering::Acquire) {
+            break;
+        }
+        let loc = Location {
+            fsid: fs_info.fsid,
+            subvol: id,
+        };
+        let root = root_tree.path(id, |parent, dir| {
+            Ok(Some(btrfs::dir_path(&fd, parent, dir)?))
+        })?;
+        info.subvol_paths.insert(loc, root.clone());
+        let mut subvol = Subvolume::new(Some(fd.clone()), root, loc, group);
+        subvol.tree_id = id;
+        subvol.send_files(quit_sig, tx, |f| {
+            Ok(sv2_arg.search_tree(&fd, id, BTRFS_INODE_ITEM_KEY, BTRFS_EXTENT_DATA_KEY, f)?)
+        })?;
+    }
+    Ok(())
+}
+
+// the subvolumes of a filesystem and where they are linked, read from its root tree
+#[derive(Debug, Default)]
+pub(crate) struct RootTree {
+    pub(crate) subvols: BTreeMap<u64, RootItem>,
+    // subvolume -> parent subvolume, directory and name
+    links: HashMap<u64, (u64, u64, Vec<u8>)>,
+}
+impl RootTree {
+    // takes the items of the root tree in any order
+    pub(crate) fn add(&mut self, item: TreeItem<'_>) {
+        let id = item.objectid();
+        let is_subvol = id == BTRFS_FS_TREE_OBJECTID
+            || (BTRFS_FIRST_FREE_OBJECTID..=BTRFS_LAST_FREE_OBJECTID).contains(&id);
+        match item.r#type() {
+            // deleted ones wait for cleanup with no refs
+            BTRFS_ROOT_ITEM_KEY if is_subvol => {
+                if let Some(root) = item.root_item().filter(|r| r.refs > 0) {
+                    self.subvols.insert(id, root);
+                }
+            }
+            BTRFS_ROOT_REF_KEY => {
+                if let Some((dir, name)) = item.root_ref() {
+                    self.links.insert(item.offset(), (id, dir, name.to_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // keeps subvolume `id` only
+    pub(crate) fn select(&mut self, id: u64) -> io::Result<()> {
+        if !self.subvols.contains_key(&id) {
+            let msg = format!("No subvolume {}", id);
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+        self.subvols.retain(|&k, _| k == id);
+        Ok(())
+    }
+
+    // below the top level subvolume, which is "/", through the subvolumes it is linked in;
+    // `dir_path` finds directory `dir` of subvolume `parent` below the subvolume's root
+    pub(crate) fn path(
+        &self,
+        id: u64,
+        mut dir_path: impl FnMut(u64, u64) -> io::Result<Option<PathBuf>>,
+    ) -> io::Result<PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = id;
+        while cur != BTRFS_FS_TREE_OBJECTID {
+            // deleted parents leave subvolumes unreachable
+            let found = match self
+                .links
+                .get(&cur)
+                .filter(|_| names.len() <= self.links.len())
+            {
+                Some((parent, dir, name)) => {
+                    dir_path(*parent, *dir)?.map(|dir| (*parent, dir, name))
+                }
+                None => None,
+            };
+            let Some((parent, dir, name)) = found else {
+                return Ok(PathBuf::from(format!("<subvolume {}>", id)));
+            };
+            names.push(dir.join(OsStr::from_bytes(name)));
+            cur = parent;
+        }
+        Ok(names
+            .iter()
+            .rev()
+            .fold(PathBuf::from("/"), |path, name| path.join(name)))
+    }
+}